@@ -0,0 +1,185 @@
+//! Benchmarks of parsing and analysis on a generated set of funds, run with `cargo bench`.
+
+use std::{
+    env,
+    ffi::OsString,
+    fmt::Write as _,
+    fs,
+    hint::black_box,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use portfolio_exposure_analyzer::{
+    config::{COUNTRY_TO_REGION, SECTORS},
+    utils::{analyze_exposure, parse_portfolio, parse_securities, Conf, Exposure},
+    ExposureBasis, ImageFormat, LabelValue, PartialHoldings, PlotlySource, Theme, WeightBy,
+    WeightUnit,
+};
+use strum::IntoEnumIterator;
+
+const FUNDS: usize = 200;
+const HOLDINGS: usize = 100;
+const NESTED: usize = 10;
+
+fn conf() -> Conf {
+    Conf {
+        limit: 20,
+        currency: "€".to_owned(),
+        currency_code: "EUR".to_owned(),
+        display: false,
+        image: false,
+        image_scale: 1.,
+        image_format: ImageFormat::SVG,
+        theme: Theme::Light,
+        unknown_in_title: false,
+        completeness_subtitle: true,
+        heat: false,
+        round_labels: false,
+        other_holdings: false,
+        weight_by: WeightBy::Capital,
+        exposure_basis: ExposureBasis::Reported,
+        reported_completeness: None,
+        min_completeness: None,
+        completeness_banner: false,
+        label_value: LabelValue::Percent,
+        plotly_source: PlotlySource::Cdn,
+        unknown_label: "Unknown".to_owned(),
+        other_label: "Other".to_owned(),
+        partial_holdings: PartialHoldings::Unknown,
+        trace_isin: None,
+        lenient_sectors: false,
+        keep_zero_weights: false,
+        weight_unit: WeightUnit::Percent,
+        normalize_weights: false,
+        rescale_weights: false,
+        empty_as_cash: false,
+        continents: false,
+        plotly_json: false,
+        raw_values: false,
+        holding_tickers: None,
+        separate_files: false,
+        strict_isin: false,
+        stats_panel: false,
+        palette: None,
+        bands: Vec::new(),
+        as_of: "2023-01-01".to_owned(),
+        output_file_name: OsString::from("bench"),
+        output_folder: String::new(),
+        round_epsilon: Some(0.05),
+    }
+}
+
+/// Write a securities file with plain funds and one fund of funds, and a portfolio holding all of them
+fn write_fixtures() -> (String, String) {
+    let mut sectors = SECTORS.iter().copied().collect::<Vec<_>>();
+    sectors.sort();
+    let mut countries = COUNTRY_TO_REGION.keys().copied().collect::<Vec<_>>();
+    countries.sort();
+
+    let isin = |fund: usize| format!("XX{:010}", fund);
+    let mut securities = String::from(
+        "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n",
+    );
+    let mut portfolio = String::from("ISIN,Amount\n");
+    for fund in 0..FUNDS {
+        for row in 0..HOLDINGS {
+            let (id, name, ter) = if row == 0 {
+                (isin(fund), format!("Fund {}", fund), "0.2")
+            } else {
+                (String::new(), String::new(), "")
+            };
+            let sector = match sectors.get(row) {
+                Some(sector) => format!("{},{:.2}", sector, 100. / sectors.len() as f32),
+                None => ",".to_owned(),
+            };
+            let country = countries.get((fund + row) % countries.len()).unwrap();
+            writeln!(
+                securities,
+                "{},{},{},Stock {},{:.2},{},{},{:.2},,",
+                id,
+                name,
+                ter,
+                (fund * 7 + row) % 1000,
+                100. / HOLDINGS as f32,
+                sector,
+                country,
+                100. / HOLDINGS as f32
+            )
+            .unwrap();
+        }
+        writeln!(portfolio, "{},{}", isin(fund), 1000 + fund).unwrap();
+    }
+    for row in 0..NESTED {
+        let (id, name, ter) = if row == 0 {
+            (isin(FUNDS), "Fund of funds".to_owned(), "0.3")
+        } else {
+            (String::new(), String::new(), "")
+        };
+        writeln!(
+            securities,
+            "{},{},{},{},{:.2},,,,,,",
+            id,
+            name,
+            ter,
+            isin(row),
+            100. / NESTED as f32
+        )
+        .unwrap();
+    }
+    writeln!(portfolio, "{},{}", isin(FUNDS), 5000).unwrap();
+
+    let dir = env::temp_dir().join("portfolio-exposure-analyzer-bench");
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str, contents: &str| -> String {
+        let path: PathBuf = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    };
+    (
+        path("securities.csv", &securities),
+        path("portfolio.csv", &portfolio),
+    )
+}
+
+/// Run the function repeatedly for about a second and print the mean time per iteration
+fn bench<T>(name: &str, mut f: impl FnMut() -> T) {
+    black_box(f());
+    let start = Instant::now();
+    let mut iterations = 0;
+    while start.elapsed() < Duration::from_secs(1) {
+        black_box(f());
+        iterations += 1;
+    }
+    println!(
+        "{:<24} {:>12.3?} ({} iterations)",
+        name,
+        start.elapsed() / iterations,
+        iterations
+    );
+}
+
+fn main() {
+    let conf = conf();
+    let (securities_file, portfolio_file) = write_fixtures();
+    let securities = parse_securities(Some(securities_file.clone()), None, &conf).unwrap();
+    let portfolio = parse_portfolio(&portfolio_file, &securities, &conf).unwrap();
+
+    bench("parse_securities", || {
+        parse_securities(Some(securities_file.clone()), None, &conf).unwrap()
+    });
+    for exposure in Exposure::iter() {
+        bench(&format!("analyze_exposure {}", exposure), || {
+            analyze_exposure(&securities, &portfolio.1, exposure, conf.analysis())
+        });
+    }
+    bench("full pipeline", || {
+        let securities = parse_securities(Some(securities_file.clone()), None, &conf).unwrap();
+        let (_, portfolio) = parse_portfolio(&portfolio_file, &securities, &conf).unwrap();
+        Exposure::iter()
+            .map(|exposure| {
+                analyze_exposure(&securities, &portfolio, exposure, conf.analysis()).unwrap()
+            })
+            .collect::<Vec<_>>()
+    });
+}
@@ -221,3 +221,393 @@ pub static COUNTRY_TO_MARKET: Lazy<HashMap<&'static str, &'static str>> = Lazy::
         ("Zimbabwe", "Standalone"),
     ])
 });
+
+/// Continents of countries, used instead of regions with `--collapse-regions-into-continents`
+pub static COUNTRY_TO_CONTINENT: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
+    HashMap::from([
+        ("United States", "North America"),
+        ("Canada", "North America"),
+        ("Brazil", "South America"),
+        ("Chile", "South America"),
+        ("Colombia", "South America"),
+        ("Mexico", "North America"),
+        ("Peru", "South America"),
+        ("Argentina", "South America"),
+        ("Bermuda", "North America"),
+        ("Jamaica", "North America"),
+        ("Panama", "North America"),
+        ("Puerto Rico", "North America"),
+        ("Trinidad & Tobago", "South America"),
+        ("Austria", "Europe"),
+        ("Belgium", "Europe"),
+        ("Denmark", "Europe"),
+        ("Finland", "Europe"),
+        ("France", "Europe"),
+        ("Germany", "Europe"),
+        ("Ireland", "Europe"),
+        ("Italy", "Europe"),
+        ("Netherlands", "Europe"),
+        ("Norway", "Europe"),
+        ("Portugal", "Europe"),
+        ("Spain", "Europe"),
+        ("Sweden", "Europe"),
+        ("Switzerland", "Europe"),
+        ("United Kingdom", "Europe"),
+        ("Czech Republic", "Europe"),
+        ("Greece", "Europe"),
+        ("Hungary", "Europe"),
+        ("Poland", "Europe"),
+        ("Croatia", "Europe"),
+        ("Estonia", "Europe"),
+        ("Iceland", "Europe"),
+        ("Latvia", "Europe"),
+        ("Lithuania", "Europe"),
+        ("Romania", "Europe"),
+        ("Serbia", "Europe"),
+        ("Slovenia", "Europe"),
+        ("Belarus", "Europe"),
+        ("Bosnia Herzegovina", "Europe"),
+        ("Bulgaria", "Europe"),
+        ("Malta", "Europe"),
+        ("Russia", "Europe"),
+        ("Ukraine", "Europe"),
+        ("Australia", "Oceania"),
+        ("Hong Kong", "Asia"),
+        ("Japan", "Asia"),
+        ("New Zealand", "Oceania"),
+        ("Singapore", "Asia"),
+        ("Israel", "Asia"),
+        ("Egypt", "Africa"),
+        ("Kuwait", "Asia"),
+        ("Qatar", "Asia"),
+        ("Saudi Arabia", "Asia"),
+        ("Turkey", "Asia"),
+        ("United Arab Emirates", "Asia"),
+        ("Bahrain", "Asia"),
+        ("Jorand", "Asia"),
+        ("Oman", "Asia"),
+        ("Lebanon", "Asia"),
+        ("Palestine", "Asia"),
+        ("China", "Asia"),
+        ("India", "Asia"),
+        ("Indonesia", "Asia"),
+        ("Korea", "Asia"),
+        ("Malaysia", "Asia"),
+        ("Philippines", "Asia"),
+        ("Taiwan", "Asia"),
+        ("Thailand", "Asia"),
+        ("Kazakhstan", "Asia"),
+        ("Bangladesh", "Asia"),
+        ("Pakistan", "Asia"),
+        ("Sri Lanka", "Asia"),
+        ("Vietnam", "Asia"),
+        ("South Africa", "Africa"),
+        ("Kenya", "Africa"),
+        ("Mauritius", "Africa"),
+        ("Morocco", "Africa"),
+        ("Nigeria", "Africa"),
+        ("Tunisia", "Africa"),
+        ("Benin", "Africa"),
+        ("Burkina Faso", "Africa"),
+        ("Côte D'Ivoire", "Africa"),
+        ("Guinea-Bissau", "Africa"),
+        ("Mali", "Africa"),
+        ("Niger", "Africa"),
+        ("Senegal", "Africa"),
+        ("Togo", "Africa"),
+        ("Botzwana", "Africa"),
+        ("Zimbabwe", "Africa"),
+    ])
+});
+
+/// Currency bloc of countries: the currency of a country together with the currencies pegged to
+/// it, eg the CFA franc in the euro bloc and the Gulf currencies in the dollar bloc
+pub static COUNTRY_TO_BLOC: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
+    HashMap::from([
+        ("United States", "Dollar Bloc"),
+        ("Bermuda", "Dollar Bloc"),
+        ("Panama", "Dollar Bloc"),
+        ("Puerto Rico", "Dollar Bloc"),
+        ("Hong Kong", "Dollar Bloc"),
+        ("Qatar", "Dollar Bloc"),
+        ("Saudi Arabia", "Dollar Bloc"),
+        ("United Arab Emirates", "Dollar Bloc"),
+        ("Bahrain", "Dollar Bloc"),
+        ("Jorand", "Dollar Bloc"),
+        ("Oman", "Dollar Bloc"),
+        ("Austria", "Euro Bloc"),
+        ("Belgium", "Euro Bloc"),
+        ("Denmark", "Euro Bloc"),
+        ("Finland", "Euro Bloc"),
+        ("France", "Euro Bloc"),
+        ("Germany", "Euro Bloc"),
+        ("Ireland", "Euro Bloc"),
+        ("Italy", "Euro Bloc"),
+        ("Netherlands", "Euro Bloc"),
+        ("Portugal", "Euro Bloc"),
+        ("Spain", "Euro Bloc"),
+        ("Greece", "Euro Bloc"),
+        ("Croatia", "Euro Bloc"),
+        ("Estonia", "Euro Bloc"),
+        ("Latvia", "Euro Bloc"),
+        ("Lithuania", "Euro Bloc"),
+        ("Slovenia", "Euro Bloc"),
+        ("Bosnia Herzegovina", "Euro Bloc"),
+        ("Bulgaria", "Euro Bloc"),
+        ("Malta", "Euro Bloc"),
+        ("Benin", "Euro Bloc"),
+        ("Burkina Faso", "Euro Bloc"),
+        ("Côte D'Ivoire", "Euro Bloc"),
+        ("Guinea-Bissau", "Euro Bloc"),
+        ("Mali", "Euro Bloc"),
+        ("Niger", "Euro Bloc"),
+        ("Senegal", "Euro Bloc"),
+        ("Togo", "Euro Bloc"),
+        ("United Kingdom", "Sterling"),
+        ("Japan", "Yen"),
+        ("Switzerland", "Swiss Franc"),
+        ("China", "Renminbi"),
+        ("Canada", "Commodity Dollars"),
+        ("Australia", "Commodity Dollars"),
+        ("New Zealand", "Commodity Dollars"),
+        ("Norway", "Nordic"),
+        ("Sweden", "Nordic"),
+        ("Iceland", "Nordic"),
+        ("Brazil", "Other Currencies"),
+        ("Chile", "Other Currencies"),
+        ("Colombia", "Other Currencies"),
+        ("Mexico", "Other Currencies"),
+        ("Peru", "Other Currencies"),
+        ("Argentina", "Other Currencies"),
+        ("Jamaica", "Other Currencies"),
+        ("Trinidad & Tobago", "Other Currencies"),
+        ("Czech Republic", "Other Currencies"),
+        ("Hungary", "Other Currencies"),
+        ("Poland", "Other Currencies"),
+        ("Romania", "Other Currencies"),
+        ("Serbia", "Other Currencies"),
+        ("Belarus", "Other Currencies"),
+        ("Russia", "Other Currencies"),
+        ("Ukraine", "Other Currencies"),
+        ("Singapore", "Other Currencies"),
+        ("Israel", "Other Currencies"),
+        ("Egypt", "Other Currencies"),
+        ("Kuwait", "Other Currencies"),
+        ("Turkey", "Other Currencies"),
+        ("Lebanon", "Other Currencies"),
+        ("Palestine", "Other Currencies"),
+        ("India", "Other Currencies"),
+        ("Indonesia", "Other Currencies"),
+        ("Korea", "Other Currencies"),
+        ("Malaysia", "Other Currencies"),
+        ("Philippines", "Other Currencies"),
+        ("Taiwan", "Other Currencies"),
+        ("Thailand", "Other Currencies"),
+        ("Kazakhstan", "Other Currencies"),
+        ("Bangladesh", "Other Currencies"),
+        ("Pakistan", "Other Currencies"),
+        ("Sri Lanka", "Other Currencies"),
+        ("Vietnam", "Other Currencies"),
+        ("South Africa", "Other Currencies"),
+        ("Kenya", "Other Currencies"),
+        ("Mauritius", "Other Currencies"),
+        ("Morocco", "Other Currencies"),
+        ("Nigeria", "Other Currencies"),
+        ("Tunisia", "Other Currencies"),
+        ("Botzwana", "Other Currencies"),
+        ("Zimbabwe", "Other Currencies"),
+    ])
+});
+
+pub static PALETTES: Lazy<HashMap<&'static str, Vec<&'static str>>> = Lazy::new(|| {
+    HashMap::from([
+        (
+            "plotly",
+            vec![
+                "#636EFA", "#EF553B", "#00CC96", "#AB63FA", "#FFA15A", "#19D3F3", "#FF6692",
+                "#B6E880", "#FF97FF", "#FECB52",
+            ],
+        ),
+        (
+            "tab10",
+            vec![
+                "#1F77B4", "#FF7F0E", "#2CA02C", "#D62728", "#9467BD", "#8C564B", "#E377C2",
+                "#7F7F7F", "#BCBD22", "#17BECF",
+            ],
+        ),
+        // Okabe-Ito colors without black, distinguishable with the common forms of color
+        // blindness and from the grays of the unknown and other labels
+        (
+            "cb-safe",
+            vec![
+                "#E69F00", "#56B4E9", "#009E73", "#F0E442", "#0072B2", "#D55E00", "#CC79A7",
+            ],
+        ),
+        (
+            "viridis",
+            vec![
+                "#440154", "#482878", "#3E4A89", "#31688E", "#26828E", "#1F9E89", "#35B779",
+                "#6DCD59", "#B4DE2C", "#FDE725",
+            ],
+        ),
+    ])
+});
+
+/// Words in a holding name that suggest it is a fund, compared in upper case
+pub static FUND_KEYWORDS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    HashSet::from([
+        "ETF",
+        "ETC",
+        "UCITS",
+        "FUND",
+        "SICAV",
+        "ISHARES",
+        "VANGUARD",
+        "LYXOR",
+        "XTRACKERS",
+        "AMUNDI",
+        "SPDR",
+        "INVESCO",
+    ])
+});
+
+/// Values of the `AssetClass` column that mark a security as fixed income, compared in upper case
+pub static FIXED_INCOME_CLASSES: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    HashSet::from([
+        "BOND",
+        "BONDS",
+        "FIXED INCOME",
+        "FIXED-INCOME",
+        "GOVERNMENT BONDS",
+        "CORPORATE BONDS",
+    ])
+});
+
+/// Symbols of common currency codes, used for `--set-currency`
+pub static CURRENCY_SYMBOLS: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
+    HashMap::from([
+        ("EUR", "€"),
+        ("USD", "$"),
+        ("GBP", "£"),
+        ("JPY", "¥"),
+        ("CHF", "CHF"),
+        ("SEK", "kr"),
+        ("NOK", "kr"),
+        ("DKK", "kr"),
+        ("PLN", "zł"),
+        ("INR", "₹"),
+    ])
+});
+
+/// Currency bloc of common currency codes, the bloc a hedged share class in that currency hedges to
+pub static CURRENCY_TO_BLOC: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
+    HashMap::from([
+        ("EUR", "Euro Bloc"),
+        ("USD", "Dollar Bloc"),
+        ("GBP", "Sterling"),
+        ("JPY", "Yen"),
+        ("CHF", "Swiss Franc"),
+        ("SEK", "Nordic"),
+        ("NOK", "Nordic"),
+        ("DKK", "Euro Bloc"),
+        ("CAD", "Commodity Dollars"),
+        ("AUD", "Commodity Dollars"),
+        ("NZD", "Commodity Dollars"),
+    ])
+});
+
+/// Coarse split of a region into markets, approximated by market capitalisation. Only used when a
+/// security has neither countries nor markets.
+pub static REGION_TO_MARKET: Lazy<HashMap<&'static str, Vec<(&'static str, f32)>>> =
+    Lazy::new(|| {
+        HashMap::from([
+            (
+                "Americas",
+                vec![
+                    ("Developed", 0.96),
+                    ("Emerging", 0.03),
+                    ("Standalone", 0.01),
+                ],
+            ),
+            (
+                "Europe",
+                vec![
+                    ("Developed", 0.97),
+                    ("Emerging", 0.02),
+                    ("Standalone", 0.01),
+                ],
+            ),
+            ("Pacific", vec![("Developed", 1.)]),
+            ("Asia", vec![("Emerging", 0.97), ("Frontier", 0.03)]),
+            (
+                "Middle East",
+                vec![("Emerging", 0.85), ("Developed", 0.12), ("Frontier", 0.03)],
+            ),
+            (
+                "Africa",
+                vec![("Emerging", 0.75), ("Frontier", 0.2), ("Standalone", 0.05)],
+            ),
+        ])
+    });
+
+/// Coarse split of a market into regions, approximated by market capitalisation. Only used when a
+/// security has neither countries nor regions.
+pub static MARKET_TO_REGION: Lazy<HashMap<&'static str, Vec<(&'static str, f32)>>> =
+    Lazy::new(|| {
+        HashMap::from([
+            (
+                "Developed",
+                vec![
+                    ("Americas", 0.74),
+                    ("Europe", 0.17),
+                    ("Pacific", 0.08),
+                    ("Middle East", 0.01),
+                ],
+            ),
+            (
+                "Emerging",
+                vec![
+                    ("Asia", 0.79),
+                    ("Americas", 0.08),
+                    ("Middle East", 0.08),
+                    ("Africa", 0.03),
+                    ("Europe", 0.02),
+                ],
+            ),
+            (
+                "Frontier",
+                vec![
+                    ("Asia", 0.4),
+                    ("Africa", 0.35),
+                    ("Europe", 0.15),
+                    ("Middle East", 0.1),
+                ],
+            ),
+            (
+                "Standalone",
+                vec![
+                    ("Americas", 0.5),
+                    ("Europe", 0.35),
+                    ("Africa", 0.1),
+                    ("Middle East", 0.05),
+                ],
+            ),
+        ])
+    });
+
+/// Coarse split of a region into continents, approximated by market capitalisation. Only used with
+/// `--collapse-regions-into-continents` when a security has no countries.
+pub static REGION_TO_CONTINENT: Lazy<HashMap<&'static str, Vec<(&'static str, f32)>>> =
+    Lazy::new(|| {
+        HashMap::from([
+            (
+                "Americas",
+                vec![("North America", 0.97), ("South America", 0.03)],
+            ),
+            ("Europe", vec![("Europe", 1.)]),
+            ("Pacific", vec![("Asia", 0.8), ("Oceania", 0.2)]),
+            ("Asia", vec![("Asia", 1.)]),
+            ("Middle East", vec![("Asia", 0.95), ("Africa", 0.05)]),
+            ("Africa", vec![("Africa", 1.)]),
+        ])
+    });
@@ -0,0 +1,138 @@
+pub mod config;
+pub mod utils;
+
+use plotly::ImageFormat as PlotlyImageFormat;
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum ImageFormat {
+    PNG,
+    JPEG,
+    WEBP,
+    SVG,
+    PDF,
+    EPS,
+}
+
+impl From<ImageFormat> for PlotlyImageFormat {
+    fn from(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::PNG => PlotlyImageFormat::PNG,
+            ImageFormat::JPEG => PlotlyImageFormat::JPEG,
+            ImageFormat::WEBP => PlotlyImageFormat::WEBP,
+            ImageFormat::SVG => PlotlyImageFormat::SVG,
+            ImageFormat::PDF => PlotlyImageFormat::PDF,
+            ImageFormat::EPS => PlotlyImageFormat::EPS,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+/// How to treat a fund of funds whose listed holdings do not add up to 100%
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum PartialHoldings {
+    /// Report the unlisted remainder as unknown
+    Unknown,
+    /// Scale the listed holdings up proportionally to 100%
+    Scale,
+    /// Use the fund's own sector, country, region and market data for the unlisted remainder
+    Fallback,
+}
+
+/// Unit of the weight columns in the securities and portfolio files
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum WeightUnit {
+    /// Percent, where 100 is the whole
+    Percent,
+    /// Basis points, where 10000 is the whole
+    Bps,
+    /// Fraction, where 1 is the whole
+    Fraction,
+}
+
+impl WeightUnit {
+    /// Value of a weight in this unit that stands for the whole
+    pub fn whole(self) -> f32 {
+        match self {
+            WeightUnit::Percent => 100.,
+            WeightUnit::Bps => 10000.,
+            WeightUnit::Fraction => 1.,
+        }
+    }
+}
+
+/// Value shown as the text of the bars
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum LabelValue {
+    /// Share of the portfolio in percent
+    Percent,
+    /// Amount in the portfolio currency, for portfolios with amounts
+    Currency,
+}
+
+/// How the generated HTML includes plotly.js
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum PlotlySource {
+    /// Load plotly.js from its CDN, keeping the file small but requiring internet to view it
+    Cdn,
+    /// Embed plotly.js in the file so it can be viewed offline
+    Embed,
+}
+
+/// What the portfolio positions are weighted by in the exposures
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum WeightBy {
+    /// Capital invested in each position
+    Capital,
+    /// Risk contribution of each position, its capital weight times the `Volatility` of the security
+    Risk,
+}
+
+/// What the exposures are a share of
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum ExposureBasis {
+    /// The whole portfolio, as reported by the fact sheets, with the gaps in the data as `Unknown`
+    Reported,
+    /// The classified part of the portfolio, scaling the known labels of each exposure to 100%
+    Classified,
+}
+
+/// What to group the holding results by
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum GroupHoldings {
+    /// Parent issuer of the holding, from the issuer file
+    Issuer,
+}
+
+/// Built-in mapping printed by the `config` command
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum Mapping {
+    /// Known sectors
+    Sectors,
+    /// Sector names mapped to a known sector
+    SectorSynonyms,
+    /// Region of every country
+    Regions,
+    /// Market of every country
+    Markets,
+    /// Continent of every country
+    Continents,
+    /// Currency bloc of every country
+    CurrencyBlocs,
+    /// Split of a region into markets, used without countries
+    RegionMarkets,
+    /// Split of a market into regions, used without countries
+    MarketRegions,
+    /// Split of a region into continents, used without countries
+    RegionContinents,
+    /// Words that mark a holding as a fund
+    FundKeywords,
+    /// Asset classes that are fixed income
+    FixedIncomeClasses,
+    /// Symbols of currency codes
+    Currencies,
+}
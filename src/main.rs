@@ -1,55 +1,240 @@
-mod config;
-mod utils;
-
-use clap::{ArgGroup, Parser};
-use tracing::error;
-use std::{error::Error, path::Path};
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    ffi::OsString,
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
 use strum::IntoEnumIterator;
-use plotly::ImageFormat as PlotlyImageFormat;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use tracing::{error, event, Event, Level, Subscriber};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
 
-use utils::{
-    analyze_exposure, calculate_ter, parse_portfolio, parse_securities, plot_grid, Conf, Exposure,
+use portfolio_exposure_analyzer::{
+    config::CURRENCY_SYMBOLS,
+    utils::{
+        add_position, analyze_exposure, append_history, browse, calculate_div_growth,
+        calculate_duration, calculate_lookthrough, calculate_net_ter, calculate_payout_ratio,
+        calculate_pe, calculate_rebalance, calculate_ter, calculate_total_cost, check_completeness,
+        check_rules, check_sector_consistency, drill_down, drop_small_positions, effective_count,
+        exclude_isins, filter_tag, find_date, find_unmapped, fingerprint, fixed_income_holdings,
+        format_date, group_holdings, holding_tickers, is_date, merge_portfolios, parse_bands,
+        parse_issuers, parse_palette, parse_portfolio, parse_rules, parse_securities, plot_grid,
+        print_comparison, print_history, print_lookthrough, print_mapping, print_rebalance,
+        print_ter, print_top_holdings, print_unexpanded_funds, print_unmapped,
+        rebase_on_classified, reconcile, report_nesting, risk_weights, set_portfolio_total,
+        write_markdown, write_xlsx, Conf, Exposure, Metrics, Security, OTHER, UNKNOWN,
+    },
+    ExposureBasis, GroupHoldings, ImageFormat, LabelValue, Mapping, PartialHoldings, PlotlySource,
+    Theme, WeightBy, WeightUnit,
 };
 
-#[derive(Debug, Copy, Clone, clap::ValueEnum)]
-pub enum ImageFormat {
-   PNG,
-   JPEG,
-   WEBP,
-   SVG,
-   PDF,
-   EPS
-}
+/// Number of warnings logged during the run
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts warning events regardless of the log filter
+struct WarningCounter;
 
-impl Into<PlotlyImageFormat> for ImageFormat {
-    fn into(self) -> PlotlyImageFormat {
-        match self {
-            ImageFormat::PNG => PlotlyImageFormat::PNG,
-            ImageFormat::JPEG => PlotlyImageFormat::JPEG,
-            ImageFormat::WEBP => PlotlyImageFormat::WEBP,
-            ImageFormat::SVG => PlotlyImageFormat::SVG,
-            ImageFormat::PDF => PlotlyImageFormat::PDF,
-            ImageFormat::EPS => PlotlyImageFormat::EPS,
+impl<S: Subscriber> Layer<S> for WarningCounter {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() == Level::WARN {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
         }
     }
 }
 
+fn parse_date(date: &str) -> Result<String, String> {
+    if is_date(date) {
+        Ok(date.to_string())
+    } else {
+        Err(format!("Bad date {}, expected YYYY-MM-DD", date))
+    }
+}
+
+/// Accept image scales between 0.1 and 10, as plotly fails on non-positive scales and huge images
+fn parse_image_scale(scale: &str) -> Result<f64, String> {
+    match scale.parse::<f64>() {
+        Ok(scale) if (0.1..=10.).contains(&scale) => Ok(scale),
+        _ => Err(format!(
+            "Bad image scale {}, expected a number between 0.1 and 10",
+            scale
+        )),
+    }
+}
+
+/// Accept percentages between 0 and 100, eg for the minimum completeness
+fn parse_percent(percent: &str) -> Result<f32, String> {
+    match percent.parse::<f32>() {
+        Ok(percent) if (0. ..=100.).contains(&percent) => Ok(percent),
+        _ => Err(format!(
+            "Bad percentage {}, expected a number between 0 and 100",
+            percent
+        )),
+    }
+}
+
+/// Split an added portfolio into its file and total value, given as `FILE=TOTAL`. A path that
+/// only contains a `=`, like `runs/date=2024/p.csv`, is a file without a total.
+fn parse_added_portfolio(portfolio: &str) -> Result<(String, Option<f32>), String> {
+    match portfolio.rsplit_once('=') {
+        Some((file_path, total)) => match total.parse::<f32>() {
+            Ok(total) if total.is_finite() => Ok((file_path.to_string(), Some(total))),
+            _ => Ok((portfolio.to_string(), None)),
+        },
+        None => Ok((portfolio.to_string(), None)),
+    }
+}
+
+/// Normalize a currency given as a code like `GBP` or a symbol like `£` to its code. Symbols shared
+/// by several currencies, like `kr`, are rejected in favour of the code.
+fn parse_currency(currency: &str) -> Result<String, String> {
+    let currency = currency.trim();
+    let mut codes = CURRENCY_SYMBOLS
+        .iter()
+        .filter(|(_, symbol)| **symbol == currency)
+        .map(|(code, _)| *code)
+        .collect::<Vec<_>>();
+    codes.sort();
+    if currency.len() == 3 && currency.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(currency.to_ascii_uppercase())
+    } else if let [code] = codes[..] {
+        Ok(code.to_string())
+    } else if !codes.is_empty() {
+        Err(format!(
+            "Currency symbol {} is ambiguous, use one of the codes {}",
+            currency,
+            codes.join(", ")
+        ))
+    } else {
+        Err(format!(
+            "Bad currency {:?}, expected a currency code like EUR or a symbol like €",
+            currency
+        ))
+    }
+}
+
 /// Simple portfolio holdings analyzer
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Logging filter
+    #[arg(long, env = "RUST_LOG", default_value = "info", global = true)]
+    log_filter: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Analyze and plot the exposure of a portfolio. This is the default command
+    Analyze(Box<AnalyzeArgs>),
+    /// Compare the exposure of two portfolios
+    Compare(CompareArgs),
+    /// Show the exposure over the runs recorded in a history file
+    History(HistoryArgs),
+    /// Print the built-in mappings
+    Config(ConfigArgs),
+    /// List the sectors and countries of the securities that are missing from the built-in
+    /// mappings, without analyzing a portfolio
+    ListUnmapped(ListUnmappedArgs),
+}
+
+/// Securities and the options for reading them and analyzing portfolios, shared by the commands
+#[derive(Args, Debug)]
+struct SecuritiesArgs {
+    /// CSV file containing asset allocation information about all the securities in your portfolio.
+    /// CSV file format is `ISIN,Name,Ticker,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight`
+    /// with an optional `Duration` column. The analyze command uses the bundled reference securities
+    /// when neither this nor --securities-dir is given
+    #[arg()]
+    securities: Option<String>,
+
+    /// Folder of per-security CSV files in the securities file format, merged with the securities file.
+    /// A file name is used as the ISIN when the file leaves the ISIN column empty
+    #[arg(long, value_name = "DIR")]
+    securities_dir: Option<String>,
+
+    /// Assign unknown sectors to "Other" with a warning instead of failing
+    #[arg(long)]
+    lenient_sectors: bool,
+
+    /// Keep the exposure entries of the securities with a weight of zero, eg to show a sector a
+    /// fund can hold at 0%, instead of dropping them
+    #[arg(long)]
+    keep_zero_weights: bool,
+
+    /// Unit of the weight columns in the securities and portfolio files
+    #[arg(long, value_enum, default_value_t = WeightUnit::Percent)]
+    weight_unit: WeightUnit,
+
+    /// Scale the sector, country, region and market weights of every security to sum to 100%
+    #[arg(long)]
+    normalize_securities_weights: bool,
+
+    /// Drop portfolio positions with a weight below this percentage and scale the rest up to 100%
+    #[arg(long, value_name = "PERCENT")]
+    min_position_weight: Option<f32>,
+
+    /// Scale the weights of a portfolio with weights to sum to exactly 100%, eg when the broker rounds them
+    #[arg(long)]
+    rescale_weights: bool,
+
+    /// Show securities without any sector, country, region, market or holding data as "Cash" in
+    /// every exposure instead of "Unknown"
+    #[arg(long)]
+    empty_securities_as_cash: bool,
+
+    /// Show the region exposure rolled up into continents
+    #[arg(long)]
+    collapse_regions_into_continents: bool,
+
+    /// Fail on malformed ISINs instead of only warning about them
+    #[arg(long)]
+    strict_isin: bool,
+
+    /// How to treat funds whose holdings include other securities but do not add up to 100%
+    #[arg(long, value_enum, default_value_t = PartialHoldings::Unknown)]
+    partial_holdings: PartialHoldings,
+
+    /// Label of the unclassified share of every exposure, eg for reports in another language
+    #[arg(long, value_name = "LABEL", default_value = UNKNOWN)]
+    unknown_label: String,
+
+    /// Label of the unknown sectors assigned by --lenient-sectors
+    #[arg(long, value_name = "LABEL", default_value = OTHER)]
+    other_label: String,
+
+    /// Round exposure totals that land within the rounding epsilon of 100% to exactly 100%
+    #[arg(long)]
+    round_to_100: bool,
+
+    /// Maximum deviation in percentage points from 100% that is reconciled by --round-to-100
+    #[arg(long, value_name = "EPSILON", default_value_t = 0.05)]
+    round_epsilon: f32,
+}
+
+#[derive(Args, Debug)]
+#[command(allow_missing_positional = true)]
 #[clap(group(
             ArgGroup::new("currency")
                 .args(&["set_currency", "eur", "usd"]),
         ))]
-struct Args {
-    /// CSV file containing asset allocation information about all the securities in your portfolio.
-    /// CSV file format is `ISIN,Name,Ticker,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight`
-    #[arg()]
-    securities: String,
+struct AnalyzeArgs {
+    #[command(flatten)]
+    source: SecuritiesArgs,
 
     /// CSV file containing information about your portfolio securities distribution.
-    /// CSV file format is `ISIN,Amount` where amount is in your currency or `ISIN,Weight` where weight is the percentage amount
+    /// CSV file format is `ISIN,Amount` where amount is in your currency or `ISIN,Weight` where weight is the percentage amount.
+    /// `Ticker` can be used instead of or in addition to `ISIN`, it is resolved through the securities file
     #[arg()]
     portfolio: String,
 
@@ -60,14 +245,59 @@ struct Args {
     #[arg(short = 'f', long, value_enum, default_value_t=ImageFormat::PNG)]
     image_format: ImageFormat,
 
-    /// Scale the output image up or down
-    #[arg(short = 's', long, default_value_t = 1.0)]
+    /// Color theme of the rendered graphs
+    #[arg(short = 't', long, value_enum, default_value_t = Theme::Light)]
+    theme: Theme,
+
+    /// Color bars from a palette by their label, so a label keeps its color between runs.
+    /// Either `plotly`, `tab10`, `viridis`, the color-blind safe `cb-safe` or a comma separated
+    /// list of `#RRGGBB` colors. Not used with --heat
+    #[arg(long)]
+    palette: Option<String>,
+
+    /// CSV file with target bands in the `Exposure,Label,Min,Max` format, eg `Region,Europe,30,40`,
+    /// shaded behind the matching bars
+    #[arg(long, value_name = "BANDS")]
+    bands: Option<String>,
+
+    /// Scale the output image up or down, between 0.1 and 10
+    #[arg(short = 's', long, default_value_t = 1.0, value_parser = parse_image_scale)]
     image_scale: f64,
 
     /// Save output to this folder. If none is provided, save output to the same folder as the portfolio
     #[arg(short = 'o', long)]
     output_folder: Option<String>,
 
+    /// Also save the plotly figure JSON, for embedding the graphs elsewhere
+    #[arg(long)]
+    plotly_json: bool,
+
+    /// Label the holding bars by ticker when known, showing the full name on hover
+    #[arg(long)]
+    ticker_labels: bool,
+
+    /// CSV file in the `Holding,Ticker` format with tickers for holdings, used with --ticker-labels
+    #[arg(long, value_name = "FILE", requires = "ticker_labels")]
+    ticker_file: Option<String>,
+
+    /// Write a standalone plot per exposure to `<PORTFOLIO>-<EXPOSURE>.html` instead of the combined grid
+    #[arg(long)]
+    separate_files: bool,
+
+    /// Leave the exposures without any data, only Unknown or nothing at all, out of the plot
+    #[arg(long)]
+    compact_grid: bool,
+
+    /// Add a panel with the top entry, HHI concentration index and unknown share of every exposure
+    /// to the HTML output
+    #[arg(long)]
+    stats_panel: bool,
+
+    /// Embed the securities contributing to every bar in the HTML output and list them when a bar
+    /// is clicked
+    #[arg(long)]
+    drill_down: bool,
+
     /// Display the fully rendered graphs in the default system browser
     #[arg(short, long)]
     display: bool,
@@ -80,80 +310,900 @@ struct Args {
     #[arg(long)]
     usd: bool,
 
-    /// Define custom portfolio currency
-    #[arg(long, value_name = "CURRENCY")]
+    /// Define custom portfolio currency as a code like `GBP` or a symbol like `£`
+    #[arg(long, value_name = "CURRENCY", value_parser = parse_currency)]
     set_currency: Option<String>,
 
+    /// Symbol of the portfolio currency shown in the charts, instead of the usual symbol of the
+    /// currency code
+    #[arg(long, value_name = "SYMBOL")]
+    currency_symbol: Option<String>,
+
+    /// Show the percentage of unclassified assets in the title of each graph
+    #[arg(long)]
+    include_unknown_in_title: bool,
+
+    /// Leave the data completeness of the exposures out of the plot title
+    #[arg(long)]
+    no_completeness_subtitle: bool,
+
+    /// Color holdings from green to red by their weight relative to an equal weight portfolio
+    #[arg(long)]
+    heat: bool,
+
+    /// Round the percentages shown on the bars with the largest remainder method, so that the
+    /// labels of an exposure add up to its total. The underlying values are not rounded
+    #[arg(long)]
+    round_labels: bool,
+
+    /// Add a last bar to the holdings graph with the combined weight of the holdings left out by
+    /// --limit, so the bars add up to the total
+    #[arg(long)]
+    other_holdings: bool,
+
+    /// Value shown as the text of the bars. Currency requires a portfolio with amounts
+    #[arg(long, value_enum, default_value_t = LabelValue::Percent)]
+    label_value: LabelValue,
+
+    /// Weight the positions in the exposures by their capital or by their risk contribution, the
+    /// capital weight times the `Volatility` column of the securities. The TER and other metrics
+    /// stay weighted by capital
+    #[arg(long, value_enum, default_value_t = WeightBy::Capital)]
+    weight_by: WeightBy,
+
+    /// Whether the exposures are a share of the whole portfolio as reported, with the gaps in the
+    /// data as Unknown, or of its classified part, scaling the known labels of each exposure to
+    /// 100%. The completeness in the titles stays the classified share as reported
+    #[arg(long, value_enum, default_value_t = ExposureBasis::Reported)]
+    exposure_basis: ExposureBasis,
+
+    /// Minimum share in percent of the portfolio that has to be classified, ie not Unknown, in
+    /// every exposure. Below it the analysis fails before writing any chart
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    min_completeness: Option<f32>,
+
+    /// Write the charts anyway when an exposure is below --min-completeness, with a warning
+    /// banner over them instead of failing
+    #[arg(long, requires = "min_completeness")]
+    completeness_banner: bool,
+
+    /// How plotly.js is included in the HTML output
+    #[arg(long, value_enum, default_value_t = PlotlySource::Embed)]
+    plotly_source: PlotlySource,
+
+    /// Print the number of distinct underlying holdings and their concentration per portfolio security
+    #[arg(long)]
+    lookthrough: bool,
+
+    /// Print the estimated annual fee in currency of every security and the whole portfolio
+    #[arg(long)]
+    explain_ter: bool,
+
+    /// Also show the TER net of securities lending income from the `SecLendingIncome` column
+    #[arg(long)]
+    net_ter: bool,
+
+    /// Stack the holdings of fixed income securities separately from the equity holdings in the
+    /// Holding graph, by the `AssetClass` of the securities listing them
+    #[arg(long)]
+    split_holdings: bool,
+
+    /// Aggregate the holding results before plotting and reporting
+    #[arg(long, value_enum, value_name = "GROUP", requires = "issuers")]
+    group_holdings_by: Option<GroupHoldings>,
+
+    /// CSV file in the `Holding,Issuer` format mapping holding names or ISINs to their issuer
+    #[arg(long, value_name = "FILE")]
+    issuers: Option<String>,
+
+    /// Print the N largest underlying holdings across the whole portfolio with cumulative weights
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Warn about funds whose stated sectors diverge by more than this many percentage points from
+    /// the sectors of their holdings looked through
+    #[arg(long, value_name = "POINTS", default_value_t = 10.)]
+    max_sector_divergence: f32,
+
+    /// Only print the largest holdings after lookthrough, 10 unless set with --top, and the
+    /// effective number of holdings, without computing the other exposures or writing any plot
+    #[arg(long)]
+    holdings_lookthrough_only: bool,
+
+    /// Print the holdings that look like funds by their name, eg `ETF` or `UCITS`, but are missing
+    /// from the securities, to find the fact sheets needed for a better lookthrough
+    #[arg(long)]
+    unexpanded_funds: bool,
+
+    /// Log the full lookthrough expansion of this ISIN and the funds it holds at INFO level
+    #[arg(long, value_name = "ISIN")]
+    trace_isin: Option<String>,
+
+    /// Browse the results of every exposure interactively in a terminal UI and drill into the
+    /// securities behind a label. Requires building with the tui feature
+    #[arg(long)]
+    tui: bool,
+
+    /// Print a fingerprint of the analysis results to detect changes in exposure between portfolios
+    #[arg(long)]
+    fingerprint: bool,
+
+    /// Append the results of this run as a JSON line to this history file
+    #[arg(long, value_name = "HISTORY")]
+    append_history: Option<String>,
+
+    /// Write the unrounded results as fractions instead of rounded percentages to the history file
+    #[arg(long, requires = "append_history")]
+    raw_values: bool,
+
+    /// Write the results as Markdown tables with a summary heading to this file, or to stdout
+    /// when no file or `-` is given
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "-")]
+    markdown: Option<String>,
+
+    /// Write the results to this XLSX workbook with a summary sheet and a sheet per exposure.
+    /// Requires building with the xlsx feature
+    #[arg(long, value_name = "FILE")]
+    xlsx: Option<String>,
+
+    /// Date of the portfolio as `YYYY-MM-DD` shown in the outputs. Taken from the portfolio file name
+    /// or else its modification time if not given
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    as_of: Option<String>,
+
+    /// Merge another portfolio file into the analysis, eg of a household member. Portfolios with
+    /// weights need their total value as `FILE=TOTAL` to be merged with others
+    #[arg(long, value_name = "FILE[=TOTAL]", value_parser = parse_added_portfolio)]
+    add_portfolio: Vec<(String, Option<f32>)>,
+
+    /// Total value of the portfolio when it has weights, needed to merge it with --add-portfolio
+    #[arg(long, value_name = "TOTAL")]
+    portfolio_total: Option<f32>,
+
+    /// Add a hypothetical position as `ISIN:amount` or `Ticker:amount` before the analysis, eg
+    /// `IE0031442068:5000`. The amount is a weight in the --weight-unit for portfolios with weights
+    #[arg(long, value_name = "POSITION")]
+    what_if: Vec<String>,
+
+    /// Drop this ISIN from the portfolio before the analysis and scale the remaining weights up to 100%
+    #[arg(long, value_name = "ISIN")]
+    exclude_isin: Vec<String>,
+
+    /// Keep the weights of the remaining securities when excluding ISINs, reporting the excluded
+    /// share as unknown
+    #[arg(long, requires = "exclude_isin")]
+    keep_weights: bool,
+
+    /// Only analyze the positions whose security has this tag, scaled up to 100%
+    #[arg(long, value_name = "TAG")]
+    filter_tag: Option<String>,
+
+    /// Hide this label, eg a country, from the results of every exposure
+    #[arg(long, value_name = "LABEL")]
+    exclude_label: Vec<String>,
+
+    /// CSV file with target weights in the portfolio file format. Prints the trades needed to rebalance
+    /// the portfolio to these targets. Requires a portfolio with amounts
+    #[arg(long, value_name = "TARGET")]
+    rebalance: Option<String>,
+
+    /// Rebalance by buying only, reporting the additional cash needed
+    #[arg(long, requires = "rebalance")]
+    no_sell: bool,
+
+    /// Portfolio file to reconcile the portfolio with, eg exported from a broker. Prints the weight
+    /// differences per ISIN and exits with a non-zero code when any exceeds the tolerance
+    #[arg(long, value_name = "REFERENCE")]
+    reconcile: Option<String>,
+
+    /// Largest weight difference per ISIN in percentage points accepted by `--reconcile`
+    #[arg(
+        long,
+        value_name = "PCT",
+        default_value_t = 0.5,
+        requires = "reconcile"
+    )]
+    reconcile_tolerance: f32,
+
+    /// CSV file with policy rules in the `Exposure,Label,Comparator,Threshold` format, eg `Market,Emerging,<=,20`,
+    /// `Holding,*,<=,5` or `TER,,<=,0.25`. Exits with a non-zero code when any rule fails
+    #[arg(long, value_name = "RULES")]
+    assert: Option<String>,
+
     /// Limit the number of data points per graph
     #[arg(short = 'l', long, default_value_t = 25)]
     limit: usize,
 
-    /// Logging filter
-    #[arg(long, env = "RUST_LOG", default_value = "info")]
-    log_filter: String,
+    /// Exit with a non-zero code if any warning was logged
+    #[arg(long)]
+    fail_on_warning: bool,
+
+    /// Print every file the analysis reads or writes as resolved from the arguments and whether
+    /// it exists, before running the analysis
+    #[arg(long)]
+    print_config_paths: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(allow_missing_positional = true)]
+#[clap(group(
+            ArgGroup::new("securities_source")
+                .args(&["securities", "securities_dir"])
+                .required(true)
+                .multiple(true),
+        ))]
+struct CompareArgs {
+    #[command(flatten)]
+    source: SecuritiesArgs,
+
+    /// Portfolio CSV file in the format of the analyze command
+    #[arg()]
+    portfolio: String,
+
+    /// Portfolio CSV file to compare with
+    #[arg(long, value_name = "OTHER")]
+    other: String,
+
+    /// Number of labels with the largest change to print per exposure
+    #[arg(short = 'l', long, default_value_t = 10)]
+    limit: usize,
+
+    /// Hide the labels whose weight changed by less than this many percentage points
+    #[arg(long, value_name = "POINTS", default_value_t = 0.)]
+    diff_threshold: f32,
+}
+
+#[derive(Args, Debug)]
+struct HistoryArgs {
+    /// History file written by the analyze command with --append-history
+    #[arg()]
+    history: String,
+
+    /// Exposure to show, one of Holding, Sector, Country, Region, Market, CurrencyBloc or Tag
+    #[arg(short = 'e', long, default_value = "Region", value_parser = parse_exposure)]
+    exposure: Exposure,
+
+    /// Label of the exposure to show. Defaults to the largest labels of the latest run
+    #[arg(long, value_name = "LABEL")]
+    label: Vec<String>,
+
+    /// Number of labels to show when none are given
+    #[arg(short = 'l', long, default_value_t = 5)]
+    limit: usize,
+
+    /// Label of the unclassified share used in the history file, left out of the default labels
+    #[arg(long, value_name = "LABEL", default_value = UNKNOWN)]
+    unknown_label: String,
+}
+
+#[derive(Args, Debug)]
+struct ConfigArgs {
+    /// Mapping to print. Prints all of them if not given
+    #[arg(value_enum)]
+    mapping: Option<Mapping>,
+}
+
+#[derive(Args, Debug)]
+#[clap(group(
+            ArgGroup::new("securities_source")
+                .args(&["securities", "securities_dir"])
+                .required(true)
+                .multiple(true),
+        ))]
+struct ListUnmappedArgs {
+    /// Securities CSV file in the format of the analyze command
+    #[arg()]
+    securities: Option<String>,
+
+    /// Folder of per-security CSV files in the securities file format
+    #[arg(long, value_name = "DIR")]
+    securities_dir: Option<String>,
+}
+
+fn parse_exposure(exposure: &str) -> Result<Exposure, String> {
+    Exposure::from_str(exposure).map_err(|_| format!("Bad exposure {}", exposure))
+}
+
+/// Run the analyze command when the arguments don't start with a command, so that
+/// `portfolio-exposure-analyzer SECURITIES PORTFOLIO` keeps working
+fn with_default_command(mut args: Vec<OsString>) -> Vec<OsString> {
+    let command = Cli::command();
+    let explicit = args.get(1).and_then(|arg| arg.to_str()).is_some_and(|arg| {
+        matches!(arg, "help" | "-h" | "--help" | "-V" | "--version")
+            || command
+                .get_subcommands()
+                .any(|subcommand| subcommand.get_name() == arg)
+    });
+    if args.len() > 1 && !explicit {
+        args.insert(1, OsString::from("analyze"));
+    }
+    args
+}
+
+/// Configuration for reading the securities and analyzing portfolios, with the output options
+/// left at their defaults
+fn base_conf(source: &SecuritiesArgs) -> Conf {
+    Conf {
+        limit: 25,
+        currency: "€".to_owned(),
+        currency_code: "EUR".to_owned(),
+        display: false,
+        image: false,
+        image_scale: 1.,
+        image_format: ImageFormat::PNG,
+        theme: Theme::Light,
+        unknown_in_title: false,
+        completeness_subtitle: true,
+        heat: false,
+        round_labels: false,
+        other_holdings: false,
+        weight_by: WeightBy::Capital,
+        exposure_basis: ExposureBasis::Reported,
+        reported_completeness: None,
+        min_completeness: None,
+        completeness_banner: false,
+        label_value: LabelValue::Percent,
+        plotly_source: PlotlySource::Embed,
+        unknown_label: source.unknown_label.clone(),
+        other_label: source.other_label.clone(),
+        partial_holdings: source.partial_holdings,
+        trace_isin: None,
+        lenient_sectors: source.lenient_sectors,
+        keep_zero_weights: source.keep_zero_weights,
+        weight_unit: source.weight_unit,
+        normalize_weights: source.normalize_securities_weights,
+        rescale_weights: source.rescale_weights,
+        empty_as_cash: source.empty_securities_as_cash,
+        continents: source.collapse_regions_into_continents,
+        plotly_json: false,
+        raw_values: false,
+        holding_tickers: None,
+        separate_files: false,
+        strict_isin: source.strict_isin,
+        stats_panel: false,
+        palette: None,
+        bands: Vec::new(),
+        as_of: format_date(SystemTime::now()),
+        output_file_name: OsString::new(),
+        output_folder: String::new(),
+        round_epsilon: source.round_to_100.then_some(source.round_epsilon),
+    }
+}
+
+/// Whether the results are of the tag exposure of a portfolio without any tags, which is left out
+fn is_untagged(exposure: Exposure, results: &[(String, f32)], conf: &Conf) -> bool {
+    exposure == Exposure::Tag && is_unclassified(results, conf)
+}
+
+/// Whether the results have no label besides Unknown, eg after excluding every other label
+fn is_unclassified(results: &[(String, f32)], conf: &Conf) -> bool {
+    results
+        .iter()
+        .all(|(label, _)| *label == conf.unknown_label)
+}
+
+/// Print the input files of the analysis with whether they were found, and the output files with
+/// whether they already exist. Paths that exist are shown resolved to absolute paths
+fn print_config_paths(args: &AnalyzeArgs, output_folder: &str) {
+    let describe = |path: &str, exists: &str, absent: &str| match fs::canonicalize(path) {
+        Ok(resolved) => format!("{} ({})", resolved.display(), exists),
+        Err(_) => format!("{} ({})", path, absent),
+    };
+    println!("Input files:");
+    let source = &args.source;
+    if source.securities.is_none() && source.securities_dir.is_none() {
+        println!("  {:<20} bundled reference securities", "Securities");
+    }
+    let inputs = [
+        ("Securities", source.securities.as_deref()),
+        ("Securities folder", source.securities_dir.as_deref()),
+        ("Portfolio", Some(args.portfolio.as_str())),
+    ]
+    .into_iter()
+    .chain(
+        args.add_portfolio
+            .iter()
+            .map(|(file_path, _)| ("Added portfolio", Some(file_path.as_str()))),
+    )
+    .chain([
+        ("Tickers", args.ticker_file.as_deref()),
+        ("Issuers", args.issuers.as_deref()),
+        ("Bands", args.bands.as_deref()),
+        ("Rules", args.assert.as_deref()),
+        ("Rebalance targets", args.rebalance.as_deref()),
+        ("Reconcile reference", args.reconcile.as_deref()),
+    ]);
+    for (name, file_path) in inputs {
+        if let Some(file_path) = file_path {
+            println!("  {:<20} {}", name, describe(file_path, "found", "missing"));
+        }
+    }
+    println!("Output files:");
+    let output_folder = if output_folder.is_empty() {
+        "."
+    } else {
+        output_folder
+    };
+    let outputs = [
+        ("Output folder", Some(output_folder)),
+        ("History", args.append_history.as_deref()),
+        ("Markdown", args.markdown.as_deref()),
+        ("Workbook", args.xlsx.as_deref()),
+    ];
+    for (name, file_path) in outputs {
+        match file_path {
+            Some("-") => println!("  {:<20} stdout", name),
+            Some(file_path) => {
+                println!("  {:<20} {}", name, describe(file_path, "exists", "new"))
+            }
+            None => {}
+        }
+    }
+}
+
+fn load_securities(
+    source: &SecuritiesArgs,
+    conf: &Conf,
+) -> Result<HashMap<String, Security>, Box<dyn Error>> {
+    parse_securities(
+        source.securities.clone(),
+        source.securities_dir.clone(),
+        conf,
+    )
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+    let cli = Cli::parse_from(with_default_command(env::args_os().collect()));
 
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
                 .with_target(false)
-                .with_filter(EnvFilter::new(args.log_filter)),
+                .with_filter(EnvFilter::new(cli.log_filter)),
         )
+        .with(WarningCounter)
         .init();
 
-    let currency = if let Some(cur) = args.set_currency.as_deref() {
+    match cli.command {
+        Command::Analyze(args) => analyze(*args),
+        Command::Compare(args) => compare(args),
+        Command::History(args) => print_history(
+            &args.history,
+            args.exposure,
+            &args.label,
+            args.limit,
+            &args.unknown_label,
+        ),
+        Command::ListUnmapped(args) => {
+            print_unmapped(&find_unmapped(args.securities, args.securities_dir)?);
+            Ok(())
+        }
+        Command::Config(args) => {
+            match args.mapping {
+                Some(mapping) => print_mapping(mapping),
+                None => {
+                    for mapping in Mapping::value_variants() {
+                        print_mapping(*mapping);
+                        println!();
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+fn compare(args: CompareArgs) -> Result<(), Box<dyn Error>> {
+    let conf = base_conf(&args.source);
+    let securities = load_securities(&args.source, &conf)?;
+    let mut exposures = Vec::new();
+    for file_path in [&args.portfolio, &args.other] {
+        let mut portfolio = parse_portfolio(file_path, &securities, &conf)?;
+        if let Some(min_weight) = args.source.min_position_weight {
+            portfolio = drop_small_positions(portfolio, min_weight)?;
+        }
+        let (_, portfolio) = portfolio;
+        exposures.push(
+            Exposure::iter()
+                .map(|exposure| {
+                    Ok((
+                        exposure,
+                        analyze_exposure(&securities, &portfolio, exposure, conf.analysis())?,
+                    ))
+                })
+                .filter(|result| {
+                    !matches!(result, Ok((exposure, result)) if is_untagged(*exposure, result, &conf))
+                })
+                .collect::<Result<Vec<_>, Box<dyn Error>>>()?,
+        );
+    }
+    let name = |file_path: &str| {
+        Path::new(file_path)
+            .file_stem()
+            .map_or(file_path.to_string(), |stem| {
+                stem.to_string_lossy().to_string()
+            })
+    };
+    print_comparison(
+        (&name(&args.portfolio), &name(&args.other)),
+        &exposures[0],
+        &exposures[1],
+        args.limit,
+        args.diff_threshold,
+    );
+    Ok(())
+}
+
+fn analyze(args: AnalyzeArgs) -> Result<(), Box<dyn Error>> {
+    let currency_code = if let Some(cur) = args.set_currency.as_deref() {
         cur.to_string()
     } else {
         let (eur, usd) = (args.eur, args.usd);
         match (eur, usd) {
-            (_, true) => "$".to_owned(),
-            _ => "€".to_owned(),
+            (_, true) => "USD".to_owned(),
+            _ => "EUR".to_owned(),
         }
     };
-
-    let securities = match parse_securities(args.securities) {
-        Ok(securities) => securities,
-        Err(err) => {
-            error!("{}", err);
-            panic!("Errors occured")
-        },
-    };
-    
-    let (total, portfolio) = parse_portfolio(&args.portfolio)?;
+    let currency = args.currency_symbol.clone().unwrap_or_else(|| {
+        CURRENCY_SYMBOLS
+            .get(currency_code.as_str())
+            .map_or(currency_code.clone(), |symbol| symbol.to_string())
+    });
 
     let output_file_name = Path::new(&args.portfolio)
         .file_stem()
-        .expect("Portfolio file name")
+        .ok_or(format!("Bad portfolio file name {}", args.portfolio))?
         .to_os_string();
-    let output_folder = if let Some(folder) = args.output_folder {
+    let output_folder = if let Some(folder) = args.output_folder.clone() {
         folder
     } else {
         Path::new(&args.portfolio)
             .parent()
-            .expect("Portfolio file path")
+            .ok_or(format!("Bad portfolio file path {}", args.portfolio))?
             .to_string_lossy()
             .to_string()
     };
-
-    let mut exposures = Vec::new();
-    for exposure in Exposure::iter() {
-        let result = analyze_exposure(&securities, &portfolio, exposure)?;
-        exposures.push((exposure, result));
+    if args.print_config_paths {
+        print_config_paths(&args, &output_folder);
     }
-    let ter = calculate_ter(&securities, &portfolio)?;
-    let conf = Conf {
+    let as_of = match args.as_of {
+        Some(date) => date,
+        None => match find_date(&output_file_name.to_string_lossy()) {
+            Some(date) => date,
+            None => format_date(
+                fs::metadata(&args.portfolio)
+                    .and_then(|metadata| metadata.modified())
+                    .unwrap_or_else(|_| SystemTime::now()),
+            ),
+        },
+    };
+    if !output_folder.is_empty() {
+        fs::create_dir_all(&output_folder)
+            .map_err(|err| format!("Failed to create output folder {}: {}", output_folder, err))?;
+    }
+
+    let mut conf = Conf {
         limit: args.limit,
         currency,
+        currency_code,
         display: args.display,
         image: args.save_image,
         image_scale: args.image_scale,
         image_format: args.image_format,
+        theme: args.theme,
+        unknown_in_title: args.include_unknown_in_title,
+        completeness_subtitle: !args.no_completeness_subtitle,
+        heat: args.heat,
+        round_labels: args.round_labels,
+        other_holdings: args.other_holdings,
+        weight_by: args.weight_by,
+        exposure_basis: args.exposure_basis,
+        min_completeness: args.min_completeness,
+        completeness_banner: args.completeness_banner,
+        label_value: args.label_value,
+        plotly_source: args.plotly_source,
+        trace_isin: args.trace_isin,
+        plotly_json: args.plotly_json,
+        raw_values: args.raw_values,
+        holding_tickers: None,
+        separate_files: args.separate_files,
+        stats_panel: args.stats_panel,
+        palette: args.palette.as_deref().map(parse_palette).transpose()?,
+        as_of,
+        bands: match args.bands.as_deref() {
+            Some(bands) => parse_bands(bands)?,
+            None => Vec::new(),
+        },
         output_file_name,
         output_folder,
+        ..base_conf(&args.source)
+    };
+
+    let securities = load_securities(&args.source, &conf)?;
+    if args.ticker_labels {
+        conf.holding_tickers = Some(holding_tickers(&securities, args.ticker_file.as_deref())?);
+    }
+
+    let mut portfolios = vec![(args.portfolio.clone(), args.portfolio_total)];
+    portfolios.extend(args.add_portfolio.iter().cloned());
+    let portfolios = portfolios
+        .into_iter()
+        .map(|(file_path, total)| {
+            let portfolio = parse_portfolio(&file_path, &securities, &conf)?;
+            let portfolio = match total {
+                Some(total) => set_portfolio_total(&file_path, portfolio, total)?,
+                None => portfolio,
+            };
+            Ok((file_path, portfolio))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    let mut portfolio = merge_portfolios(portfolios)?;
+    let mismatches = match args.reconcile.as_deref() {
+        Some(reference) => {
+            let (_, reference) = parse_portfolio(reference, &securities, &conf)?;
+            reconcile(
+                &securities,
+                &portfolio.1,
+                &reference,
+                args.reconcile_tolerance,
+            )
+        }
+        None => 0,
+    };
+    for position in &args.what_if {
+        portfolio = add_position(portfolio, &securities, position, &conf)?;
+    }
+    if !args.exclude_isin.is_empty() {
+        portfolio = exclude_isins(portfolio, &args.exclude_isin, !args.keep_weights)?;
+    }
+    if let Some(min_weight) = args.source.min_position_weight {
+        portfolio = drop_small_positions(portfolio, min_weight)?;
+    }
+    if let Some(tag) = &args.filter_tag {
+        portfolio = filter_tag(portfolio, &securities, tag)?;
+    }
+    let (total, portfolio) = portfolio;
+    if matches!(conf.label_value, LabelValue::Currency) && total.is_none() {
+        return Err("Currency bar labels require a portfolio with amounts".into());
+    }
+    if let (ExposureBasis::Classified, LabelValue::Currency) =
+        (conf.exposure_basis, conf.label_value)
+    {
+        return Err("Currency bar labels can't be used with the classified exposure basis".into());
+    }
+    let risk_portfolio;
+    let (exposure_portfolio, mut plot_total) = match conf.weight_by {
+        WeightBy::Capital => (&portfolio, total),
+        WeightBy::Risk if matches!(conf.label_value, LabelValue::Currency) => {
+            return Err("Currency bar labels can't be used when weighting by risk".into());
+        }
+        // Risk shares have no value in currency
+        WeightBy::Risk => {
+            risk_portfolio = risk_weights(&securities, &portfolio)?;
+            (&risk_portfolio, None)
+        }
+    };
+
+    check_sector_consistency(
+        &securities,
+        &portfolio,
+        conf.analysis(),
+        args.max_sector_divergence,
+    )?;
+    let issuers = match args.issuers.as_deref() {
+        Some(issuers) => parse_issuers(issuers)?,
+        None => HashMap::new(),
+    };
+    if args.holdings_lookthrough_only {
+        let mut holdings = analyze_exposure(
+            &securities,
+            exposure_portfolio,
+            Exposure::Holding,
+            conf.analysis(),
+        )?;
+        holdings.retain(|(label, _)| !args.exclude_label.contains(label));
+        if let Some(GroupHoldings::Issuer) = args.group_holdings_by {
+            holdings = group_holdings(holdings, &issuers, &conf.unknown_label);
+        }
+        if let ExposureBasis::Classified = conf.exposure_basis {
+            rebase_on_classified(&mut holdings, &conf.unknown_label);
+        }
+        print_top_holdings(&holdings, args.top.unwrap_or(10), &conf.unknown_label);
+        match effective_count(&holdings, &conf.unknown_label) {
+            Some(count) => println!("Effective number of holdings: {:.1}", count),
+            None => println!("No holdings known"),
+        }
+        exit_on_failures(0, mismatches, args.fail_on_warning);
+        return Ok(());
+    }
+    let mut exposures = Vec::new();
+    let mut reported_completeness = Vec::new();
+    for exposure in Exposure::iter() {
+        let mut result =
+            analyze_exposure(&securities, exposure_portfolio, exposure, conf.analysis())?;
+        if is_untagged(exposure, &result, &conf) {
+            continue;
+        }
+        result.retain(|(label, _)| !args.exclude_label.contains(label));
+        if let (Exposure::Holding, Some(GroupHoldings::Issuer)) = (exposure, args.group_holdings_by)
+        {
+            result = group_holdings(result, &issuers, &conf.unknown_label);
+        }
+        if let ExposureBasis::Classified = conf.exposure_basis {
+            let classified = rebase_on_classified(&mut result, &conf.unknown_label);
+            reported_completeness.push((exposure, classified));
+        }
+        exposures.push((exposure, result));
+    }
+    if let ExposureBasis::Classified = conf.exposure_basis {
+        // Rebased shares have no value in currency
+        plot_total = None;
+        conf.reported_completeness = Some(reported_completeness);
+    }
+    // Checked before any output is written, leaving out the exposures --compact-grid drops
+    let gated = exposures
+        .iter()
+        .filter(|(_, results)| !(args.compact_grid && is_unclassified(results, &conf)))
+        .cloned()
+        .collect::<Vec<_>>();
+    check_completeness(&gated, &conf)?;
+    let fixed_income = if args.split_holdings {
+        let mut split = fixed_income_holdings(&securities, exposure_portfolio, conf.analysis())?;
+        if let Some(GroupHoldings::Issuer) = args.group_holdings_by {
+            split = group_holdings(split, &issuers, &conf.unknown_label);
+        }
+        // Rebased like the holdings they are a part of
+        let classified = conf.reported_completeness.as_ref().and_then(|reported| {
+            reported
+                .iter()
+                .find(|(exposure, _)| *exposure == Exposure::Holding)
+                .map(|(_, classified)| *classified)
+        });
+        if let Some(classified) = classified.filter(|classified| *classified > 0.) {
+            for (_, v) in split.iter_mut() {
+                *v *= 100. / classified;
+            }
+        }
+        (!split.is_empty()).then(|| split.into_iter().collect::<HashMap<_, _>>())
+    } else {
+        None
+    };
+    report_nesting(&securities, &portfolio);
+    let ter = calculate_ter(&securities, &portfolio)?;
+    if args.explain_ter {
+        let total = total.ok_or("Explaining the TER requires a portfolio with amounts")?;
+        print_ter(&securities, &portfolio, total, &conf.currency);
+    }
+    let net_ter = if args.net_ter {
+        calculate_net_ter(&securities, &portfolio)?
+    } else {
+        None
+    };
+    let total_cost = calculate_total_cost(&securities, &portfolio)?;
+    let duration = calculate_duration(&securities, &portfolio)?;
+    let pe = calculate_pe(&securities, &portfolio)?;
+    let metrics = Metrics {
+        ter,
+        net_ter,
+        total_cost,
+        duration,
+        pe,
+        div_growth: calculate_div_growth(&securities, &portfolio)?,
+        payout_ratio: calculate_payout_ratio(&securities, &portfolio)?,
+    };
+    if let Some(history) = args.append_history.as_deref() {
+        append_history(history, &exposures, total, &metrics, &conf)?;
+    }
+    if let Some(markdown) = args.markdown.as_deref() {
+        write_markdown(markdown, &exposures, plot_total, &metrics, &conf)?;
+    }
+    if let Some(xlsx) = args.xlsx.as_deref() {
+        write_xlsx(xlsx, &exposures, plot_total, &metrics, &conf)?;
+    }
+    if args.fingerprint {
+        println!("{}", fingerprint(&exposures));
+    }
+    if args.lookthrough {
+        let lookthrough = calculate_lookthrough(&securities, &portfolio, conf.analysis())?;
+        print_lookthrough(&securities, &lookthrough);
+    }
+    let holdings = exposures
+        .iter()
+        .find(|(exposure, _)| matches!(exposure, Exposure::Holding))
+        .map(|(_, holdings)| holdings);
+    if let (Some(count), Some(holdings)) = (args.top, holdings) {
+        print_top_holdings(holdings, count, &conf.unknown_label);
+    }
+    if let (true, Some(holdings)) = (args.unexpanded_funds, holdings) {
+        print_unexpanded_funds(holdings, &conf.unknown_label);
+    }
+    if args.tui {
+        browse(&securities, &portfolio, &exposures, &conf)?;
+    }
+    if let Some(target) = args.rebalance.as_deref() {
+        let total = total.ok_or("Rebalancing requires a portfolio with amounts")?;
+        let (_, target) = parse_portfolio(target, &securities, &conf)?;
+        let trades = calculate_rebalance(&portfolio, total, &target, args.no_sell);
+        print_rebalance(&securities, &trades, &conf.currency);
+    }
+    let failed_rules = match args.assert.as_deref() {
+        Some(rules) => check_rules(&parse_rules(rules)?, &exposures, ter, &conf.unknown_label),
+        None => 0,
     };
-    plot_grid(exposures, total, ter, &conf)?;
+    let drill_down = args
+        .drill_down
+        .then(|| drill_down(&securities, exposure_portfolio, &exposures, &conf))
+        .transpose()?;
+    if args.compact_grid {
+        let (empty, plotted): (Vec<_>, Vec<_>) = exposures
+            .into_iter()
+            .partition(|(_, results)| is_unclassified(results, &conf));
+        if plotted.is_empty() {
+            return Err("No exposure has any data to plot".into());
+        }
+        if !empty.is_empty() {
+            event!(
+                Level::INFO,
+                "Left out exposures without data: {}",
+                empty
+                    .iter()
+                    .map(|(exposure, _)| exposure.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        exposures = plotted;
+    }
+    plot_grid(
+        exposures,
+        plot_total,
+        &metrics,
+        drill_down.as_ref(),
+        fixed_income.as_ref(),
+        &conf,
+    )?;
+    exit_on_failures(failed_rules, mismatches, args.fail_on_warning);
     Ok(())
 }
+
+/// Exit with a non-zero code when policy rules failed, the reconciliation found mismatches, or
+/// with `fail_on_warning` when any warning was logged
+fn exit_on_failures(failed_rules: usize, mismatches: usize, fail_on_warning: bool) {
+    let warnings = WARNINGS.load(Ordering::Relaxed);
+    if fail_on_warning && warnings > 0 {
+        error!("{} warnings logged", warnings);
+    }
+    if failed_rules > 0 || mismatches > 0 || (fail_on_warning && warnings > 0) {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn default_command() {
+        assert_eq!(
+            with_default_command(args(&["pea", "file.csv"])),
+            args(&["pea", "analyze", "file.csv"])
+        );
+        // Options of the analyze command also start it
+        assert_eq!(
+            with_default_command(args(&["pea", "--usd", "file.csv"])),
+            args(&["pea", "analyze", "--usd", "file.csv"])
+        );
+        for explicit in [
+            &["pea", "compare", "a", "b"][..],
+            &["pea", "analyze", "file.csv"],
+            &["pea", "--help"],
+            &["pea"],
+        ] {
+            assert_eq!(with_default_command(args(explicit)), args(explicit));
+        }
+    }
+}
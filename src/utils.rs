@@ -1,132 +1,436 @@
-use std::{collections::HashMap, error::Error, ffi::OsString, fs::File};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    error::Error,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
-    config::{COUNTRY_TO_MARKET, COUNTRY_TO_REGION, SECTORS, SECTOR_SYNONYMS},
-    ImageFormat,
+    config::{
+        COUNTRY_TO_BLOC, COUNTRY_TO_CONTINENT, COUNTRY_TO_MARKET, COUNTRY_TO_REGION,
+        CURRENCY_SYMBOLS, CURRENCY_TO_BLOC, FIXED_INCOME_CLASSES, FUND_KEYWORDS, MARKET_TO_REGION,
+        PALETTES, REGION_TO_CONTINENT, REGION_TO_MARKET, SECTORS, SECTOR_SYNONYMS,
+    },
+    ExposureBasis, ImageFormat, LabelValue, Mapping, PartialHoldings, PlotlySource, Theme,
+    WeightBy, WeightUnit,
 };
 use plotly::{
-    color::NamedColor,
-    common::{HoverInfo, Marker, Title},
-    layout::{Axis, GridPattern, LayoutGrid},
+    color::{NamedColor, Rgb},
+    common::{Anchor, Font, HoverInfo, Marker, Title},
+    layout::{
+        themes::PLOTLY_DARK, Annotation, Axis, GridPattern, LayoutGrid, Shape, ShapeLayer,
+        ShapeLine, ShapeType,
+    },
     Bar, ImageFormat as PlotlyImageFormat, Layout, Plot,
 };
-use strum::{Display, EnumIter};
+use plotly_kaleido::Kaleido;
+use strum::{Display, EnumIter, EnumString};
 use tracing::{error, event, instrument, Level};
 
 const Y_AXIS_TITLE: &str = "% Net assets";
+const RISK_Y_AXIS_TITLE: &str = "% Risk contribution";
+/// Default label of the unclassified share of an exposure
+pub const UNKNOWN: &str = "Unknown";
+/// Default label of unknown sectors with `--lenient-sectors`
+pub const OTHER: &str = "Other";
+const CASH: &str = "Cash";
+const FIXED_INCOME: &str = "Fixed Income";
+/// Float rounding noise in percentage points tolerated in exposure totals above 100%
+const TOTAL_NOISE: f32 = 1e-3;
+/// TER in percent above which a security is most likely mis-parsed
+const MAX_PLAUSIBLE_TER: f32 = 5.;
 
-#[derive(Debug, Copy, Clone, EnumIter, Display, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, EnumIter, EnumString, Display, PartialEq, Eq, Hash)]
+#[strum(ascii_case_insensitive)]
 pub enum Exposure {
     Holding,
     Sector,
     Country,
     Region,
     Market,
+    CurrencyBloc,
+    Tag,
 }
 
+/// Exposure weights of a security. Labels are shared between securities, as the same holdings,
+/// sectors and countries repeat across funds.
+type Weights = HashMap<Arc<str>, f32>;
+
 #[derive(Debug, Default)]
 pub struct Security {
     name: String,
+    ticker: String,
     ter: f32,
-    holding: HashMap<String, f32>,
-    sector: HashMap<String, f32>,
-    country: HashMap<String, f32>,
-    region: HashMap<String, f32>,
-    market: HashMap<String, f32>,
+    transaction_cost: Option<f32>,
+    sec_lending_income: Option<f32>,
+    duration: Option<f32>,
+    pe: Option<f32>,
+    div_growth: Option<f32>,
+    payout_ratio: Option<f32>,
+    volatility: Option<f32>,
+    fixed_income: bool,
+    /// Share of the foreign currency exposure hedged back to the fund currency, from 0 to 1
+    currency_hedge: Option<f32>,
+    holding: Weights,
+    sector: Weights,
+    country: Weights,
+    region: Weights,
+    market: Weights,
+    currency_bloc: Weights,
+    tag: Weights,
 }
 
 impl Security {
-    fn get_exposure(&self, exposure: Exposure) -> &HashMap<String, f32> {
+    fn get_exposure(&self, exposure: Exposure) -> &Weights {
         match exposure {
             Exposure::Holding => &self.holding,
             Exposure::Sector => &self.sector,
             Exposure::Country => &self.country,
             Exposure::Region => &self.region,
             Exposure::Market => &self.market,
+            Exposure::CurrencyBloc => &self.currency_bloc,
+            Exposure::Tag => &self.tag,
         }
     }
 
-    fn get_exposure_mut(&mut self, exposure: Exposure) -> &mut HashMap<String, f32> {
+    fn get_exposure_mut(&mut self, exposure: Exposure) -> &mut Weights {
         match exposure {
             Exposure::Holding => &mut self.holding,
             Exposure::Sector => &mut self.sector,
             Exposure::Country => &mut self.country,
             Exposure::Region => &mut self.region,
             Exposure::Market => &mut self.market,
+            Exposure::CurrencyBloc => &mut self.currency_bloc,
+            Exposure::Tag => &mut self.tag,
         }
     }
 }
 
 pub struct Conf {
     pub limit: usize,
+    /// Currency symbol shown in the charts and printed tables
     pub currency: String,
+    /// ISO 4217 code of the currency written to data outputs
+    pub currency_code: String,
     pub display: bool,
     pub image: bool,
     pub image_scale: f64,
     pub image_format: ImageFormat,
+    pub theme: Theme,
+    pub unknown_in_title: bool,
+    pub completeness_subtitle: bool,
+    pub heat: bool,
+    pub label_value: LabelValue,
+    pub round_labels: bool,
+    pub other_holdings: bool,
+    pub weight_by: WeightBy,
+    pub exposure_basis: ExposureBasis,
+    /// Classified share of each exposure before rebasing it with `ExposureBasis::Classified`
+    pub reported_completeness: Option<Vec<(Exposure, f32)>>,
+    pub min_completeness: Option<f32>,
+    pub completeness_banner: bool,
+    pub plotly_source: PlotlySource,
+    pub unknown_label: String,
+    pub other_label: String,
+    pub partial_holdings: PartialHoldings,
+    pub trace_isin: Option<String>,
+    pub lenient_sectors: bool,
+    pub keep_zero_weights: bool,
+    pub weight_unit: WeightUnit,
+    pub normalize_weights: bool,
+    pub rescale_weights: bool,
+    pub empty_as_cash: bool,
+    pub continents: bool,
+    pub plotly_json: bool,
+    pub raw_values: bool,
+    pub holding_tickers: Option<HashMap<String, String>>,
+    pub separate_files: bool,
+    pub strict_isin: bool,
+    pub stats_panel: bool,
+    pub palette: Option<Vec<String>>,
+    pub bands: Vec<Band>,
+    pub as_of: String,
     pub output_file_name: OsString,
     pub output_folder: String,
+    pub round_epsilon: Option<f32>,
+}
+
+/// Parameters of the exposure analysis, apart from how its results are displayed
+#[derive(Clone, Copy)]
+pub struct AnalysisOptions<'a> {
+    pub partial_holdings: PartialHoldings,
+    pub trace_isin: Option<&'a str>,
+    pub round_epsilon: Option<f32>,
+    pub unknown_label: &'a str,
+}
+
+impl Conf {
+    pub fn analysis(&self) -> AnalysisOptions<'_> {
+        AnalysisOptions {
+            partial_holdings: self.partial_holdings,
+            trace_isin: self.trace_isin.as_deref(),
+            round_epsilon: self.round_epsilon,
+            unknown_label: &self.unknown_label,
+        }
+    }
+}
+
+/// Portfolio level metrics shown in the plot title
+pub struct Metrics {
+    pub ter: f32,
+    pub net_ter: Option<f32>,
+    pub total_cost: Option<f32>,
+    pub duration: Option<f32>,
+    pub pe: Option<f32>,
+    pub div_growth: Option<f32>,
+    pub payout_ratio: Option<f32>,
+}
+
+/// Acceptable range of an exposure label, shaded behind its bar
+#[derive(Debug)]
+pub struct Band {
+    exposure: Exposure,
+    label: String,
+    min: f32,
+    max: f32,
+}
+
+/// Policy rule checked against the analysis results with `--assert`
+#[derive(Debug)]
+pub struct Rule {
+    /// Exposure the rule applies to, `None` for the portfolio TER
+    exposure: Option<Exposure>,
+    /// Exposure label, `*` to check every label of the exposure
+    label: String,
+    comparator: Comparator,
+    threshold: f32,
+}
+
+#[derive(Debug, Copy, Clone, EnumString, Display)]
+enum Comparator {
+    #[strum(serialize = "<")]
+    Less,
+    #[strum(serialize = "<=")]
+    LessOrEqual,
+    #[strum(serialize = ">")]
+    Greater,
+    #[strum(serialize = ">=")]
+    GreaterOrEqual,
+}
+
+impl Comparator {
+    fn holds(self, value: f32, threshold: f32) -> bool {
+        match self {
+            Comparator::Less => value < threshold,
+            Comparator::LessOrEqual => value <= threshold,
+            Comparator::Greater => value > threshold,
+            Comparator::GreaterOrEqual => value >= threshold,
+        }
+    }
 }
 
 type Record = HashMap<String, String>;
+type Portfolio = (Option<f32>, HashMap<String, f32>);
+type Lookthrough = (String, usize, f32, f32);
+/// Divergence of the stated sectors of a fund from those of its holdings looked through, the
+/// share of the fund covered by its holdings and the sector with the largest gap, with its stated
+/// and looked through share
+type SectorDivergence = (f32, f32, (String, f32, f32));
+/// A securities file and the ISIN of its rows before the first ISIN
+type SecuritiesFile = (PathBuf, Option<String>);
+/// ISINs using a label, keyed by the dimension the label is missing from and the label
+pub type Unmapped = BTreeMap<(String, String), BTreeSet<String>>;
 
-#[instrument(skip(file_path))]
+#[instrument(skip(file_path, securities, conf))]
 pub fn parse_portfolio(
     file_path: &str,
-) -> Result<(Option<f32>, HashMap<String, f32>), Box<dyn Error>> {
+    securities: &HashMap<String, Security>,
+    conf: &Conf,
+) -> Result<Portfolio, Box<dyn Error>> {
     let file = File::open(file_path)?;
     let mut errors = Vec::new();
     let mut portfolio = HashMap::<String, f32>::new();
     let mut rdr = csv::ReaderBuilder::new()
         .comment(Some(b'#'))
         .from_reader(file);
-    let percent = {
+    let (percent, shares) = {
         // We nest this call in its own scope because of lifetimes.
         let headers = rdr.headers()?.iter().collect::<Vec<_>>();
         if headers.contains(&"Weight") {
             event!(Level::TRACE, "Securities with weights");
-            true
+            (true, false)
         } else if headers.contains(&"Amount") {
             event!(Level::TRACE, "Securities with total amounts");
-            false
+            (false, false)
+        } else if headers.contains(&"Shares") && headers.contains(&"Price") {
+            event!(Level::TRACE, "Securities with share counts and prices");
+            (false, true)
         } else {
-            panic!("Bad CSV header {:?}", headers);
+            return Err(format!(
+                "Bad CSV header {:?} in {}, expected a Weight or Amount column, or Shares and Price columns",
+                headers, file_path
+            )
+            .into());
         }
     };
-    let allocation_header = if percent { "Weight" } else { "Amount" };
-    for result in rdr.deserialize() {
-        let record: Record = result?;
-        let isin = record.get("ISIN").unwrap();
-        let allocation = record
+    let allocation_header = match (percent, shares) {
+        (true, _) => "Weight",
+        (false, false) => "Amount",
+        (false, true) => "Shares",
+    };
+    let headers = rdr.headers()?.clone();
+    check_duplicate_headers(&headers, file_path)?;
+    // Allocation columns other than the one the portfolio is read by, which must not be filled
+    // on their own as their values would be ignored
+    let ignored_headers = ["Weight", "Amount", "Shares"]
+        .into_iter()
+        .filter(|header| *header != allocation_header && headers.iter().any(|h| h == *header))
+        .collect::<Vec<_>>();
+    let mut lines = HashMap::<String, u64>::new();
+    if !headers.iter().any(|h| h == "ISIN" || h == "Ticker") {
+        return Err(format!(
+            "Bad CSV header {:?} in {}, expected ISIN or Ticker column",
+            headers, file_path
+        )
+        .into());
+    }
+    for result in rdr.records() {
+        let row = result?;
+        let line = row.position().map_or(0, |pos| pos.line());
+        let record: Record = row.deserialize(Some(&headers))?;
+        let isin = match record.get("ISIN").filter(|isin| !isin.is_empty()) {
+            Some(isin) => {
+                if let Err(err) = validate_isin(isin) {
+                    let message = format!("Portfolio ISIN {} on line {}: {}", isin, line, err);
+                    if conf.strict_isin {
+                        errors.push(message);
+                        continue;
+                    }
+                    event!(Level::WARN, "{}", message);
+                }
+                // A ticker given next to the ISIN has to name the same security
+                let ticker = record.get("Ticker").filter(|ticker| !ticker.is_empty());
+                let resolved = ticker.and_then(|ticker| resolve_ticker(securities, ticker).ok());
+                if let (Some(ticker), Some(resolved)) = (ticker, resolved) {
+                    if resolved != *isin {
+                        errors.push(format!(
+                            "Portfolio ISIN {} has ticker {} of ISIN {}",
+                            isin, ticker, resolved
+                        ));
+                        continue;
+                    }
+                }
+                isin.clone()
+            }
+            None => {
+                let ticker = record.get("Ticker").map(String::as_str).unwrap_or_default();
+                match resolve_ticker(securities, ticker) {
+                    Ok(isin) => isin,
+                    Err(err) => {
+                        errors.push(err);
+                        continue;
+                    }
+                }
+            }
+        };
+        let value = record
             .get(allocation_header)
-            .unwrap()
-            .parse::<f32>()
-            .unwrap();
-        if percent && allocation > 100. {
+            .map(String::as_str)
+            .unwrap_or_default();
+        if let Some(first_line) = lines.insert(isin.clone(), line) {
+            errors.push(format!(
+                "Portfolio ISIN {} is on both line {} and line {}, combine them into one position",
+                isin, first_line, line
+            ));
+            continue;
+        }
+        let ignored = ignored_headers
+            .iter()
+            .filter(|header| record.get(**header).is_some_and(|value| !value.is_empty()))
+            .collect::<Vec<_>>();
+        if value.is_empty() && !ignored.is_empty() {
+            errors.push(format!(
+                "Portfolio ISIN {} on line {} has a value in the {} column but none in the {} column the portfolio is read by",
+                isin, line, ignored[0], allocation_header
+            ));
+            continue;
+        }
+        let Some(mut allocation) = parse_number(value) else {
+            errors.push(format!(
+                "Portfolio ISIN {} has bad {} {:?} on line {}",
+                isin, allocation_header, value, line
+            ));
+            continue;
+        };
+        if shares {
+            let price = record.get("Price").map(String::as_str).unwrap_or_default();
+            match parse_number(price) {
+                Some(price) => allocation *= price,
+                None => {
+                    errors.push(format!(
+                        "Portfolio ISIN {} has bad Price {:?} on line {}",
+                        isin, price, line
+                    ));
+                    continue;
+                }
+            }
+        }
+        if percent && allocation > conf.weight_unit.whole() {
             errors.push(format!(
-                "Portfolio ISIN {} weight {} > 100%",
-                isin, allocation
+                "Portfolio ISIN {} weight {} > {}",
+                isin,
+                allocation,
+                conf.weight_unit.whole()
             ));
             continue;
         }
-        portfolio.entry(isin.clone()).or_insert_with(|| allocation);
+        portfolio.insert(isin, allocation);
     }
     if !errors.is_empty() {
         for err in &errors {
             error!("{}", err);
         }
-        panic!("Errors occured");
+        return Err(format!("{} errors in portfolio {}", errors.len(), file_path).into());
+    }
+    if portfolio.is_empty() {
+        return Err(format!("Portfolio {} contains no positions", file_path).into());
     }
     let total = if !percent {
         let total = portfolio.values().fold(0., |acc, v| acc + v);
+        if total <= 0. || !total.is_finite() {
+            return Err(format!("Portfolio {} has a total value of {}", file_path, total).into());
+        }
         for val in portfolio.values_mut() {
-            *val = *val / total;
+            *val /= total;
         }
         event!(Level::INFO, "Portfolio total value {:.2}", total);
         Some(total)
     } else {
         for val in portfolio.values_mut() {
-            *val /= 100.;
+            *val /= conf.weight_unit.whole();
+        }
+        if conf.rescale_weights {
+            let sum = portfolio.values().sum::<f32>();
+            if sum <= 0. || !sum.is_finite() {
+                return Err(
+                    format!("Portfolio {} has a total weight of {}", file_path, sum).into(),
+                );
+            }
+            for val in portfolio.values_mut() {
+                *val /= sum;
+            }
+            event!(
+                Level::INFO,
+                "Rescaled portfolio weights summing to {:.2}% by a factor of {:.4}",
+                sum * 100.,
+                1. / sum
+            );
         }
         None
     };
@@ -139,399 +443,5567 @@ pub fn parse_portfolio(
     Ok((total, portfolio))
 }
 
-#[instrument(skip(file_path))]
-pub fn parse_securities(file_path: String) -> Result<HashMap<String, Security>, Box<dyn Error>> {
-    let file = File::open(file_path)?;
-    let mut securities = HashMap::<String, Security>::new();
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut last_isin = String::new();
-    for result in rdr.deserialize() {
-        let record: Record = result?;
-        let mut isin: String = record.get("ISIN").unwrap().to_string();
-        if isin.is_empty() && !last_isin.is_empty() {
-            isin = last_isin.clone();
-        } else if !isin.is_empty() {
-            last_isin = isin.clone();
-        }
-        let name = record.get("Name").unwrap();
-        let ter = record.get("TER").unwrap().parse::<f32>().unwrap_or(0.);
-        let holding = record.get("Holding").unwrap();
-        let holding_weight = record
-            .get("HoldingWeight")
-            .unwrap()
-            .parse::<f32>()
-            .map(|v| v / 100.)
-            .unwrap_or(0.);
-        let mut sector = record.get("Sector").unwrap().clone();
-        if !sector.is_empty() && !SECTORS.contains(sector.as_str()) {
-            sector = SECTOR_SYNONYMS
-                .get(sector.as_str())
-                .ok_or(format!("Unknown sector {} in record {:?}", sector, record))?
-                .clone()
-                .to_string();
-        }
-        let sector_weight = record
-            .get("SectorWeight")
-            .unwrap()
-            .parse::<f32>()
-            .map(|v| v / 100.)
-            .unwrap_or(0.);
-        let country = record.get("Country").unwrap();
-        let country_weight = record
-            .get("CountryWeight")
-            .unwrap()
-            .parse::<f32>()
-            .map(|v| v / 100.)
-            .unwrap_or(0.);
-        let region = record.get("Region").unwrap();
-        let region_weight = record
-            .get("RegionWeight")
-            .unwrap()
-            .parse::<f32>()
-            .map(|v| v / 100.)
-            .unwrap_or(0.);
-        securities
-            .entry(isin.clone().to_string())
-            .and_modify(|security| {
-                if !name.is_empty() {
-                    security.name = name.clone();
-                }
-                if ter > 0.0 {
-                    security.ter = ter;
-                }
-                if holding_weight > 0.0 {
-                    security.holding.insert(holding.clone(), holding_weight);
-                }
-                if sector_weight > 0.0 {
-                    security.sector.insert(sector.clone(), sector_weight);
-                }
-                if country_weight > 0.0 {
-                    security.country.insert(country.clone(), country_weight);
-                }
-                if region_weight > 0.0 {
-                    security.region.insert(region.clone(), region_weight);
-                }
-            })
-            .or_insert_with(|| {
-                let mut security = Security {
-                    name: name.clone(),
-                    ter,
-                    ..Default::default()
-                };
-                if holding_weight > 0.0 {
-                    security.holding.insert(holding.clone(), holding_weight);
-                }
-                if sector_weight > 0.0 {
-                    security.sector.insert(sector.clone(), sector_weight);
-                }
-                if country_weight > 0.0 {
-                    security.country.insert(country.clone(), country_weight);
-                }
-                if region_weight > 0.0 {
-                    security.region.insert(region.clone(), region_weight);
-                }
-                security
-            });
+/// Fail on repeated column names, as only one of the columns would be used
+fn check_duplicate_headers(
+    headers: &csv::StringRecord,
+    file_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut seen = HashSet::new();
+    let duplicates = headers
+        .iter()
+        .filter(|header| !seen.insert(*header))
+        .collect::<BTreeSet<_>>();
+    if duplicates.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Duplicate columns {} in {}",
+            duplicates.into_iter().collect::<Vec<_>>().join(", "),
+            file_path
+        )
+        .into())
     }
-    for (isin, security) in securities.iter_mut() {
-        for (exposure, country_map) in [
-            (Exposure::Region, &COUNTRY_TO_REGION),
-            (Exposure::Market, &COUNTRY_TO_MARKET),
-        ] {
-            if security.get_exposure(exposure).is_empty() && !security.country.is_empty() {
-                let security_countries = security.country.clone();
-                for (country, weight) in security_countries.iter() {
-                    let exp = country_map
-                        .get(country.as_str())
-                        .ok_or(format!("{} {} not defined", country, exposure))?
-                        .clone()
-                        .to_string();
-                    security
-                        .get_exposure_mut(exposure)
-                        .entry(exp)
-                        .and_modify(|v| *v += *weight)
-                        .or_insert(*weight);
-                }
-                event!(
-                    Level::TRACE,
-                    "Calculated {} for {} [{}]: {:?}",
-                    exposure,
-                    isin,
-                    security.name,
-                    security.get_exposure(exposure)
-                );
-            }
+}
+
+/// Merge portfolios into one by value, eg the portfolios of a household. Portfolios with weights
+/// have no value of their own, so each must come with a total value to be merged with others.
+pub fn merge_portfolios(
+    mut portfolios: Vec<(String, Portfolio)>,
+) -> Result<Portfolio, Box<dyn Error>> {
+    if let [(_, portfolio)] = &mut portfolios[..] {
+        return Ok(std::mem::take(portfolio));
+    }
+    let mut amounts = HashMap::<String, f32>::new();
+    for (file_path, (total, portfolio)) in portfolios {
+        let total = total.ok_or(format!(
+            "Portfolio {} has weights but no total value, set it with --portfolio-total or --add-portfolio FILE=TOTAL to merge it with other portfolios",
+            file_path
+        ))?;
+        for (isin, weight) in portfolio {
+            *amounts.entry(isin).or_insert(0.) += weight * total;
         }
     }
+    let total = amounts.values().sum::<f32>();
+    for val in amounts.values_mut() {
+        *val /= total;
+    }
     event!(
         Level::INFO,
-        "Parsed {} securities into database",
-        securities.len()
+        "Merged portfolios with total value {:.2} into {} securities",
+        total,
+        amounts.len()
     );
-    Ok(securities)
+    Ok((Some(total), amounts))
 }
 
-#[instrument(skip(securities, exposure, results, base_weight), name = "calc", fields(weight=base_weight))]
-fn calc_exposure(
+/// Give a portfolio with weights the total value `total`. Portfolios with amounts already have one.
+pub fn set_portfolio_total(
+    file_path: &str,
+    (current, portfolio): Portfolio,
+    total: f32,
+) -> Result<Portfolio, Box<dyn Error>> {
+    if current.is_some() {
+        return Err(format!(
+            "Portfolio {} has amounts, its total value cannot be set",
+            file_path
+        )
+        .into());
+    }
+    Ok((Some(total), portfolio))
+}
+
+/// Add a hypothetical position given as `ISIN:amount` or `Ticker:amount` to the portfolio,
+/// renormalizing the weights. The amount is in the portfolio currency, or a weight in the weight
+/// unit for portfolios with weights.
+pub fn add_position(
+    (total, mut portfolio): Portfolio,
     securities: &HashMap<String, Security>,
-    exposure: Exposure,
-    isin: &str,
-    base_weight: f32,
-    results: &mut HashMap<String, f32>,
-) -> Result<(), Box<dyn Error>> {
-    event!(Level::TRACE, "Calculating exposure");
-    let security = securities
-        .get(isin)
-        .ok_or(format!("ISIN {} not found in securities", isin))?;
-    // First try to see if any of the holdings is actually an ETF/fund itself that would need expanding
-    let holdings = security.get_exposure(Exposure::Holding);
-    for (holding, weight) in holdings {
-        if securities.contains_key(holding) {
-            event!(
-                Level::TRACE,
-                "Recursing for holding {}, weight {}",
-                holding,
-                weight
-            );
-            calc_exposure(securities, exposure, holding, base_weight * weight, results)?;
+    position: &str,
+    conf: &Conf,
+) -> Result<Portfolio, Box<dyn Error>> {
+    let (key, value) = position
+        .rsplit_once(':')
+        .ok_or(format!("Bad position {:?}, expected ISIN:amount", position))?;
+    let isin = if securities.contains_key(key) {
+        key.to_owned()
+    } else {
+        resolve_ticker(securities, key)?
+    };
+    let amount = parse_number(value)
+        .filter(|amount| *amount > 0.)
+        .ok_or(format!("Bad amount {:?} for position {}", value, key))?;
+    let (total, added) = match total {
+        Some(total) => (Some(total + amount), amount / (total + amount)),
+        None => (None, amount / (conf.weight_unit.whole() + amount)),
+    };
+    for val in portfolio.values_mut() {
+        *val *= 1. - added;
+    }
+    *portfolio.entry(isin.clone()).or_insert(0.) += added;
+    event!(
+        Level::INFO,
+        "Added {} of {} to the portfolio, now {:.2}%",
+        value,
+        isin,
+        portfolio.get(&isin).copied().unwrap_or_default() * 100.
+    );
+    Ok((total, portfolio))
+}
+
+/// Drop the given ISINs from the portfolio. When `renormalize` is set the remaining weights are
+/// scaled up to 100%, otherwise the excluded share is reported as unknown.
+pub fn exclude_isins(
+    (total, mut portfolio): Portfolio,
+    isins: &[String],
+    renormalize: bool,
+) -> Result<Portfolio, Box<dyn Error>> {
+    for isin in isins {
+        if portfolio.remove(isin).is_none() {
             event!(
-                Level::DEBUG,
-                "Results after holding {}: {:?}",
-                holding,
-                results
+                Level::WARN,
+                "Excluded ISIN {} is not in the portfolio",
+                isin
             );
         }
     }
-    let exposure_items = security.get_exposure(exposure);
-    for (exposure_item, weight) in exposure_items.iter() {
-        if exposure == Exposure::Holding && securities.contains_key(exposure_item) {
-            continue;
-        }
-        event!(
-            Level::TRACE,
-            "{} exposure: {}->{}",
-            exposure_item,
-            weight,
-            weight * base_weight
-        );
-        results
-            .entry(exposure_item.to_owned())
-            .and_modify(|v| {
-                *v += weight * base_weight;
-            })
-            .or_insert_with(|| weight * base_weight);
+    let remaining = portfolio.values().sum::<f32>();
+    if remaining <= 0. {
+        return Err("No positions left in the portfolio after exclusions".into());
     }
-    Ok(())
+    if !renormalize {
+        return Ok((total, portfolio));
+    }
+    for val in portfolio.values_mut() {
+        *val /= remaining;
+    }
+    event!(
+        Level::INFO,
+        "Excluded {:.2}% of the portfolio",
+        (1. - remaining) * 100.
+    );
+    Ok((total.map(|total| total * remaining), portfolio))
 }
 
-pub fn analyze_exposure(
+/// Weight the portfolio positions by their risk contribution, their weight times the `Volatility`
+/// of their security, scaled to 100%
+pub fn risk_weights(
     securities: &HashMap<String, Security>,
     portfolio: &HashMap<String, f32>,
-    exposure: Exposure,
-) -> Result<Vec<(String, f32)>, Box<dyn Error>> {
-    let mut results: HashMap<String, f32> = HashMap::new();
-    let mut errors = Vec::new();
+) -> Result<HashMap<String, f32>, Box<dyn Error>> {
+    let mut missing = Vec::new();
+    let mut risks = HashMap::new();
     for (isin, weight) in portfolio {
-        let mut isin_results: HashMap<String, f32> = HashMap::new();
-        let result = calc_exposure(securities, exposure, isin, *weight, &mut isin_results);
-        match result {
-            Ok(_) => {
-                event!(Level::DEBUG, "Results for {}: {:?}", isin, isin_results);
-                for (key, val) in isin_results.into_iter() {
-                    results
-                        .entry(key.clone())
-                        .and_modify(|share| {
-                            event!(
-                                Level::TRACE,
-                                "Modifying {}: {}->{}",
-                                key,
-                                *share,
-                                *share + val
-                            );
-                            *share += val
-                        })
-                        .or_insert_with(|| val);
-                }
-            }
-            Err(err) => {
-                errors.push(err.to_string());
+        match securities
+            .get(isin)
+            .and_then(|security| security.volatility)
+            .filter(|volatility| *volatility >= 0.)
+        {
+            Some(volatility) => {
+                risks.insert(isin.clone(), weight * volatility);
             }
+            None => missing.push(isin.as_str()),
         }
     }
-    if !errors.is_empty() {
-        for err in &errors {
-            error!("{}", err);
-        }
-        panic!("Errors occured");
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(format!(
+            "Weighting by risk requires a Volatility for every position, missing for {}",
+            missing.join(", ")
+        )
+        .into());
     }
-    let mut results = results
-        .into_iter()
-        .map(|(k, v)| (k, v * 100.))
-        .collect::<Vec<_>>();
-    let total = results.iter().fold(0., |acc, (_, v)| acc + *v);
-    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    if total < 100. {
-        results.push(("Unknown".to_string(), 100. - total));
-    } else if total > 100. {
-        panic!("Total {}% > 100%", total);
+    let total = risks.values().sum::<f32>();
+    if total <= 0. {
+        return Err("Weighting by risk requires a position with a positive Volatility".into());
     }
-    event!(Level::DEBUG, "Analysis results: {:?}", results);
-    Ok(results)
+    for risk in risks.values_mut() {
+        *risk /= total;
+    }
+    event!(
+        Level::INFO,
+        "Weighted {} positions by their risk contribution",
+        risks.len()
+    );
+    Ok(risks)
 }
 
-#[instrument(skip_all, name = "calc")]
-pub fn calculate_ter(
+/// Drop the positions with a weight below `min_weight` percent and scale the rest up to 100%
+pub fn drop_small_positions(
+    (total, mut portfolio): Portfolio,
+    min_weight: f32,
+) -> Result<Portfolio, Box<dyn Error>> {
+    let before = portfolio.len();
+    portfolio.retain(|_, weight| *weight * 100. >= min_weight);
+    if portfolio.is_empty() {
+        return Err(format!("Portfolio has no positions of at least {}%", min_weight).into());
+    }
+    if portfolio.len() == before {
+        return Ok((total, portfolio));
+    }
+    let remaining = portfolio.values().sum::<f32>();
+    event!(
+        Level::INFO,
+        "Dropped {} positions below {}% with a combined weight of {:.2}%",
+        before - portfolio.len(),
+        min_weight,
+        (1. - remaining) * 100.
+    );
+    for val in portfolio.values_mut() {
+        *val /= remaining;
+    }
+    Ok((total.map(|total| total * remaining), portfolio))
+}
+
+/// Keep only the positions whose security carries `tag` and scale them up to 100%, so a sleeve of
+/// the portfolio can be analyzed on its own
+pub fn filter_tag(
+    (total, portfolio): Portfolio,
     securities: &HashMap<String, Security>,
-    portfolio: &HashMap<String, f32>,
-) -> Result<f32, Box<dyn Error>> {
-    let mut ter = 0.0;
-    for (isin, weight) in portfolio {
-        let security = securities
-            .get(isin)
-            .ok_or(format!("ISIN {} not found in securities", isin))?;
-        ter += security.ter * weight;
+    tag: &str,
+) -> Result<Portfolio, Box<dyn Error>> {
+    let portfolio = portfolio
+        .into_iter()
+        .filter(|(isin, _)| {
+            securities
+                .get(isin)
+                .is_some_and(|security| security.tag.contains_key(tag))
+        })
+        .collect::<HashMap<_, _>>();
+    let remaining = portfolio.values().sum::<f32>();
+    if remaining <= 0. {
+        return Err(format!("No positions in the portfolio are tagged {}", tag).into());
     }
-    event!(Level::INFO, "Calculated portfolio TER: {:.3}%", ter);
-    Ok(ter)
+    event!(
+        Level::INFO,
+        "Tag {} is {:.2}% of the portfolio",
+        tag,
+        remaining * 100.
+    );
+    let portfolio = portfolio
+        .into_iter()
+        .map(|(isin, val)| (isin, val / remaining))
+        .collect();
+    Ok((total.map(|total| total * remaining), portfolio))
 }
 
-pub fn plot_grid(
-    data: Vec<(Exposure, Vec<(String, f32)>)>,
-    total: Option<f32>,
-    ter: f32,
-    conf: &Conf,
-) -> Result<(), Box<dyn Error>> {
-    let mut plot = Plot::new();
+/// Parse a weight with `parse_number`, ignoring trailing units as in `3.5 %` or `3.5pct`. Also
+/// returns whether anything was stripped from the value.
+fn parse_weight(value: &str) -> Option<(f32, bool)> {
+    let value = value.trim();
+    let number = value
+        .trim_end_matches(|c: char| !c.is_ascii_digit() && !matches!(c, ',' | '.'))
+        .trim_end();
+    parse_number(number).map(|weight| (weight, number.len() < value.len()))
+}
 
-    let mut layout = Layout::new()
-        .title(Title::new(
-            format!(
-                "Asset exposure for {} portfolio, TER {:.3}%",
-                conf.output_file_name.to_string_lossy(),
-                ter
-            )
-            .as_str(),
-        ))
-        .height(1024)
-        .grid(
-            LayoutGrid::new()
-                .rows(data.len())
-                .columns(1)
-                .pattern(GridPattern::Independent),
-        )
-        .show_legend(false);
-    for (idx, (exposure, data)) in data.into_iter().enumerate() {
-        match idx {
-            0 => {
-                layout = layout
-                    .x_axis(Axis::new().title(Title::new(exposure.to_string().as_str())))
-                    .y_axis(Axis::new().title(Title::new(Y_AXIS_TITLE)));
+/// Parse a number as written in broker exports and statements, ignoring the currency codes and
+/// symbols of `CURRENCY_SYMBOLS`, percent signs and whitespace. Both `,` and `.` are accepted as
+/// decimal and thousands separators: the last separator is the decimal one, unless it is the only
+/// separator, repeats or is a `,` followed by exactly three digits, as in `1,234`. Anything else,
+/// eg an exponent or a stray letter, makes the number invalid.
+fn parse_number(value: &str) -> Option<f32> {
+    let mut value = value
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '%')
+        .collect::<String>();
+    for (code, symbol) in CURRENCY_SYMBOLS.iter() {
+        value = value.replace(code, "").replace(symbol, "");
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, ',' | '.' | '-' | '+'))
+    {
+        return None;
+    }
+    let separators = value.matches([',', '.']).count();
+    let decimal = value.rfind([',', '.']).filter(|&idx| {
+        let same = value.matches(&value[idx..idx + 1]).count();
+        same < separators
+            || (same == 1 && (&value[idx..idx + 1] == "." || value.len() - idx - 1 != 3))
+    });
+    let number = value
+        .char_indices()
+        .filter_map(|(idx, c)| match c {
+            ',' | '.' if Some(idx) == decimal => Some('.'),
+            ',' | '.' => None,
+            c => Some(c),
+        })
+        .collect::<String>();
+    number
+        .parse::<f32>()
+        .ok()
+        .filter(|number| number.is_finite())
+}
+
+/// Validate the format and check digit of an ISIN
+fn validate_isin(isin: &str) -> Result<(), String> {
+    let bytes = isin.as_bytes();
+    if bytes.len() != 12 {
+        return Err(format!("expected 12 characters, found {}", isin.len()));
+    }
+    if !bytes[..2].iter().all(u8::is_ascii_uppercase)
+        || !bytes[2..11]
+            .iter()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        || !bytes[11].is_ascii_digit()
+    {
+        return Err("expected a country code, 9 alphanumeric characters and a check digit".into());
+    }
+    // Letters expand to two digits (A=10 .. Z=35) before applying the Luhn algorithm
+    let digits = bytes[..11]
+        .iter()
+        .flat_map(|b| match b {
+            b'0'..=b'9' => vec![b - b'0'],
+            _ => {
+                let value = b - b'A' + 10;
+                vec![value / 10, value % 10]
             }
-            1 => {
-                layout = layout
-                    .x_axis2(Axis::new().title(Title::new(exposure.to_string().as_str())))
-                    .y_axis2(Axis::new().title(Title::new(Y_AXIS_TITLE)));
+        })
+        .collect::<Vec<_>>();
+    let sum = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, digit)| {
+            let digit = if idx % 2 == 0 { digit * 2 } else { *digit };
+            (digit / 10 + digit % 10) as u32
+        })
+        .sum::<u32>();
+    let check = (10 - sum % 10) % 10;
+    if check != (bytes[11] - b'0') as u32 {
+        return Err(format!("invalid check digit, expected {}", check));
+    }
+    Ok(())
+}
+
+fn resolve_ticker(securities: &HashMap<String, Security>, ticker: &str) -> Result<String, String> {
+    if ticker.is_empty() {
+        return Err("Portfolio entry has neither ISIN nor Ticker".to_string());
+    }
+    let mut matches = securities
+        .iter()
+        .filter(|(_, security)| security.ticker.eq_ignore_ascii_case(ticker))
+        .map(|(isin, _)| isin.clone())
+        .collect::<Vec<_>>();
+    match matches.len() {
+        0 => Err(format!(
+            "Portfolio ticker {} not found in securities",
+            ticker
+        )),
+        1 => {
+            let isin = matches.remove(0);
+            event!(Level::TRACE, "Resolved ticker {} to ISIN {}", ticker, isin);
+            Ok(isin)
+        }
+        _ => {
+            matches.sort();
+            Err(format!(
+                "Portfolio ticker {} is ambiguous, matches ISINs {}",
+                ticker,
+                matches.join(", ")
+            ))
+        }
+    }
+}
+
+/// Reference securities used when no securities are given, the data of `example/securities.csv`
+const BUNDLED_SECURITIES: &str = include_str!("../example/securities.csv");
+
+/// Move the hedged share of every foreign currency bloc to the home bloc
+fn hedge_currency_bloc(currency_bloc: &mut Weights, hedge: f32, home_bloc: &str) {
+    let mut hedged = 0.;
+    for (bloc, weight) in currency_bloc.iter_mut() {
+        if &**bloc != home_bloc && &**bloc != CASH {
+            hedged += *weight * hedge;
+            *weight *= 1. - hedge;
+        }
+    }
+    if hedged > 0. {
+        *currency_bloc.entry(Arc::from(home_bloc)).or_default() += hedged;
+    }
+    currency_bloc.retain(|_, weight| *weight > 0.);
+}
+
+#[instrument(skip_all)]
+pub fn parse_securities(
+    file_path: Option<String>,
+    securities_dir: Option<String>,
+    conf: &Conf,
+) -> Result<HashMap<String, Security>, Box<dyn Error>> {
+    let mut securities = HashMap::<String, Security>::new();
+    let mut labels = HashSet::<Arc<str>>::new();
+    if file_path.is_none() && securities_dir.is_none() {
+        event!(
+            Level::WARN,
+            "No securities given, using the bundled reference securities"
+        );
+        parse_securities_file(
+            Path::new("bundled securities"),
+            Box::new(BUNDLED_SECURITIES.as_bytes()),
+            None,
+            conf,
+            &mut labels,
+            &mut securities,
+        )?;
+    }
+    for (path, fallback_isin) in securities_files(file_path, securities_dir)? {
+        parse_securities_file(
+            &path,
+            open_securities(&path)?,
+            fallback_isin.as_deref(),
+            conf,
+            &mut labels,
+            &mut securities,
+        )?;
+    }
+    // Hedged share classes are held in the portfolio currency, so that is what they hedge back to
+    let home_bloc = CURRENCY_TO_BLOC.get(conf.currency_code.as_str());
+    if home_bloc.is_none()
+        && securities
+            .values()
+            .any(|security| security.currency_hedge.is_some_and(|hedge| hedge > 0.))
+    {
+        event!(
+            Level::WARN,
+            "No currency bloc known for {} to apply the CurrencyHedge of securities to, treating \
+             them as unhedged",
+            conf.currency_code
+        );
+    }
+    // Collect every unmapped country, region and market before failing so the config can be fixed
+    // in one pass
+    let mut unmapped = BTreeMap::<(String, String), BTreeSet<String>>::new();
+    let region_map = if conf.continents {
+        &COUNTRY_TO_CONTINENT
+    } else {
+        &COUNTRY_TO_REGION
+    };
+    let exposures = [
+        Exposure::Holding,
+        Exposure::Sector,
+        Exposure::Country,
+        Exposure::Region,
+        Exposure::Market,
+        Exposure::CurrencyBloc,
+    ];
+    for (isin, security) in securities.iter_mut() {
+        // Rows without any exposure data, eg a cash line, would otherwise be Unknown everywhere
+        if exposures
+            .iter()
+            .all(|exposure| security.get_exposure(*exposure).is_empty())
+        {
+            if conf.empty_as_cash {
+                event!(
+                    Level::INFO,
+                    "Security {} [{}] has no exposure data, treating it as {}",
+                    isin,
+                    security.name,
+                    CASH
+                );
+                for exposure in exposures {
+                    security
+                        .get_exposure_mut(exposure)
+                        .insert(Arc::from(CASH), 1.);
+                }
+            } else {
+                event!(
+                    Level::WARN,
+                    "Security {} [{}] has no exposure data and is Unknown in every exposure",
+                    isin,
+                    security.name
+                );
             }
-            2 => {
-                layout = layout
-                    .x_axis3(Axis::new().title(Title::new(exposure.to_string().as_str())))
-                    .y_axis3(Axis::new().title(Title::new(Y_AXIS_TITLE)));
+        }
+        // Continents are derived from countries even when regions are given
+        if conf.continents && !security.country.is_empty() {
+            security.region.clear();
+        }
+        for (exposure, country_map) in [
+            (Exposure::Region, region_map),
+            (Exposure::Market, &COUNTRY_TO_MARKET),
+            (Exposure::CurrencyBloc, &COUNTRY_TO_BLOC),
+        ] {
+            if security.get_exposure(exposure).is_empty() && !security.country.is_empty() {
+                let security_countries = security.country.clone();
+                for (country, weight) in security_countries.iter() {
+                    let Some(exp) = country_map.get(&**country) else {
+                        unmapped
+                            .entry((country.to_string(), exposure.to_string()))
+                            .or_default()
+                            .insert(isin.clone());
+                        continue;
+                    };
+                    let exp = Arc::<str>::from(*exp);
+                    security
+                        .get_exposure_mut(exposure)
+                        .entry(exp)
+                        .and_modify(|v| *v += *weight)
+                        .or_insert(*weight);
+                }
+                event!(
+                    Level::TRACE,
+                    "Calculated {} for {} [{}]: {:?}",
+                    exposure,
+                    isin,
+                    security.name,
+                    security.get_exposure(exposure)
+                );
             }
-            3 => {
-                layout = layout
-                    .x_axis4(Axis::new().title(Title::new(exposure.to_string().as_str())))
-                    .y_axis4(Axis::new().title(Title::new(Y_AXIS_TITLE)));
+        }
+        if let (Some(hedge), Some(home_bloc)) = (security.currency_hedge, home_bloc) {
+            hedge_currency_bloc(&mut security.currency_bloc, hedge, home_bloc);
+        }
+        // Without countries, fall back to a coarse split of the market into regions or vice versa
+        for (exposure, source, split_map) in [
+            (Exposure::Region, Exposure::Market, &MARKET_TO_REGION),
+            (Exposure::Market, Exposure::Region, &REGION_TO_MARKET),
+        ] {
+            if security.get_exposure(exposure).is_empty()
+                && !security.get_exposure(source).is_empty()
+            {
+                let source_weights = security.get_exposure(source).clone();
+                for (label, weight) in source_weights.iter() {
+                    let Some(split) = split_map.get(&**label) else {
+                        unmapped
+                            .entry((label.to_string(), exposure.to_string()))
+                            .or_default()
+                            .insert(isin.clone());
+                        continue;
+                    };
+                    for (exp, share) in split {
+                        *security
+                            .get_exposure_mut(exposure)
+                            .entry(Arc::from(*exp))
+                            .or_insert(0.) += weight * share;
+                    }
+                }
+                event!(
+                    Level::TRACE,
+                    "Estimated {} from {} for {} [{}]: {:?}",
+                    exposure,
+                    source,
+                    isin,
+                    security.name,
+                    security.get_exposure(exposure)
+                );
             }
-            4 => {
-                layout = layout
-                    .x_axis5(Axis::new().title(Title::new(exposure.to_string().as_str())))
-                    .y_axis5(Axis::new().title(Title::new(Y_AXIS_TITLE)));
+        }
+        if conf.continents && security.country.is_empty() {
+            for (region, weight) in std::mem::take(&mut security.region) {
+                let Some(split) = REGION_TO_CONTINENT.get(&*region) else {
+                    unmapped
+                        .entry((region.to_string(), "Continent".to_string()))
+                        .or_default()
+                        .insert(isin.clone());
+                    continue;
+                };
+                for (continent, share) in split {
+                    *security.region.entry(Arc::from(*continent)).or_insert(0.) += weight * share;
+                }
             }
-            _ => {}
+            event!(
+                Level::TRACE,
+                "Collapsed regions into continents for {} [{}]: {:?}",
+                isin,
+                security.name,
+                security.region
+            );
         }
-        let data = if data.len() > conf.limit {
-            data.into_iter().take(conf.limit).collect()
-        } else {
-            data
-        };
-        let labels = data
-            .iter()
-            .map(|(v, _)| format!("{}", v.to_owned()))
+        if conf.normalize_weights {
+            for exposure in [
+                Exposure::Sector,
+                Exposure::Country,
+                Exposure::Region,
+                Exposure::Market,
+                Exposure::CurrencyBloc,
+            ] {
+                let weights = security.get_exposure_mut(exposure);
+                let sum = weights.values().sum::<f32>();
+                if sum <= 0. || (sum - 1.).abs() < 0.0001 {
+                    continue;
+                }
+                for weight in weights.values_mut() {
+                    *weight /= sum;
+                }
+                event!(
+                    Level::INFO,
+                    "Normalized {} weights of {} from {:.2}% to 100%",
+                    exposure,
+                    isin,
+                    sum * 100.
+                );
+            }
+        }
+    }
+    if !unmapped.is_empty() {
+        let missing = unmapped
+            .into_iter()
+            .map(|((country, exposure), isins)| {
+                format!(
+                    "  {} {} not defined (used by {})",
+                    country,
+                    exposure,
+                    isins.into_iter().collect::<Vec<_>>().join(", ")
+                )
+            })
             .collect::<Vec<_>>();
-        let values = data.iter().map(|(_, v)| *v).collect::<Vec<_>>();
+        return Err(format!(
+            "{} mappings missing from config:\n{}",
+            missing.len(),
+            missing.join("\n")
+        )
+        .into());
+    }
+    event!(
+        Level::INFO,
+        "Parsed {} securities into database",
+        securities.len()
+    );
+    Ok(securities)
+}
 
-        if exposure == Exposure::Holding {
-            let weights = values
-                .iter()
-                .map(|v| format!("{:.2}%", v))
-                .collect::<Vec<_>>();
-            let mut trace = Bar::new(labels, values.clone())
-                .hover_info(HoverInfo::None)
-                .text_array(weights)
-                .name("")
-                .marker(Marker::new())
-                .x_axis(format!("x{}", idx + 1))
-                .y_axis(format!("y{}", idx + 1));
-            if let Some(total) = total {
-                let totals = values
-                    .iter()
-                    .map(|v| format!("{:.0} {}", *v * total / 100., conf.currency))
-                    .collect::<Vec<_>>();
-                trace = trace
-                    .hover_info(HoverInfo::Text)
-                    .hover_template_array(totals);
+/// The securities file followed by the CSV files in `securities_dir` in name order, each with the
+/// ISIN used for rows before the first ISIN of the file
+fn securities_files(
+    file_path: Option<String>,
+    securities_dir: Option<String>,
+) -> Result<Vec<SecuritiesFile>, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    if let Some(file_path) = file_path {
+        paths.push((PathBuf::from(file_path), None));
+    }
+    if let Some(dir) = securities_dir {
+        let mut files = fs::read_dir(&dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        files.retain(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"))
+        });
+        files.sort();
+        event!(
+            Level::DEBUG,
+            "Found {} securities files in {}",
+            files.len(),
+            dir
+        );
+        for path in files {
+            let fallback_isin = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string());
+            paths.push((path, fallback_isin));
+        }
+    }
+    Ok(paths)
+}
+
+/// Open a securities file as CSV. SQLite databases are read from their `securities` table or view
+/// when built with the `sqlite` feature.
+fn open_securities(file_path: &Path) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    let sqlite = file_path.extension().is_some_and(|ext| {
+        ["sqlite", "sqlite3"]
+            .iter()
+            .any(|sqlite| ext.eq_ignore_ascii_case(sqlite))
+    });
+    #[cfg(feature = "sqlite")]
+    if sqlite {
+        return read_sqlite(file_path);
+    }
+    #[cfg(not(feature = "sqlite"))]
+    if sqlite {
+        return Err(format!(
+            "{} has an SQLite database extension, reading securities from SQLite requires \
+             building with --features sqlite. A CSV file should have the .csv extension",
+            file_path.display()
+        )
+        .into());
+    }
+    let file = File::open(file_path)
+        .map_err(|err| format!("Failed to open {}: {}", file_path.display(), err))?;
+    Ok(Box::new(file))
+}
+
+/// Read the rows of the `securities` table or view of an SQLite database as the records of a
+/// securities file, where NULL values become empty fields. The CSV writer quotes the values that
+/// contain separators or line breaks, so they reach the parser unchanged
+#[cfg(feature = "sqlite")]
+fn read_sqlite(file_path: &Path) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    use rusqlite::{types::ValueRef, Connection, OpenFlags};
+
+    let failed = |err: rusqlite::Error| {
+        format!(
+            "Failed to read the securities table of {}: {}",
+            file_path.display(),
+            err
+        )
+    };
+    let connection =
+        Connection::open_with_flags(file_path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(failed)?;
+    let mut statement = connection
+        .prepare("SELECT * FROM securities")
+        .map_err(failed)?;
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(statement.column_names())?;
+    let columns = statement.column_count();
+    let mut rows = statement.query([]).map_err(failed)?;
+    while let Some(row) = rows.next().map_err(failed)? {
+        let mut record = Vec::with_capacity(columns);
+        for idx in 0..columns {
+            record.push(match row.get_ref(idx).map_err(failed)? {
+                ValueRef::Null => String::new(),
+                ValueRef::Integer(value) => value.to_string(),
+                ValueRef::Real(value) => value.to_string(),
+                ValueRef::Text(value) => String::from_utf8_lossy(value).into_owned(),
+                ValueRef::Blob(_) => {
+                    return Err(format!(
+                        "Column {} of the securities table of {} holds a blob",
+                        row.as_ref().column_name(idx).map_err(failed)?,
+                        file_path.display()
+                    )
+                    .into())
+                }
+            });
+        }
+        writer.write_record(&record)?;
+    }
+    let contents = writer.into_inner().map_err(|err| err.to_string())?;
+    Ok(Box::new(std::io::Cursor::new(contents)))
+}
+
+/// Find the sectors and countries of the securities files that are missing from the config
+pub fn find_unmapped(
+    file_path: Option<String>,
+    securities_dir: Option<String>,
+) -> Result<Unmapped, Box<dyn Error>> {
+    let mut unmapped = Unmapped::new();
+    for (path, fallback_isin) in securities_files(file_path, securities_dir)? {
+        let mut rdr = csv::Reader::from_reader(open_securities(&path)?);
+        let headers = rdr.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|header| header == name);
+        let (isin_col, sector_col, country_col) =
+            (column("ISIN"), column("Sector"), column("Country"));
+        let mut last_isin = fallback_isin.unwrap_or_default();
+        for record in rdr.records() {
+            let record = record?;
+            let field =
+                |col: Option<usize>| col.and_then(|col| record.get(col)).unwrap_or_default();
+            if !field(isin_col).is_empty() {
+                last_isin = field(isin_col).to_string();
             }
-            plot.add_trace(trace);
-        } else {
-            for (k, v) in data.into_iter() {
-                let mut trace = Bar::new(vec![k.clone()], vec![v])
-                    .name("")
-                    .x_axis(format!("x{}", idx + 1))
-                    .y_axis(format!("y{}", idx + 1))
-                    .text(format!("{:.2}%", v))
-                    .hover_info(HoverInfo::None)
-                    .marker(if k.eq("Unknown") {
-                        Marker::new().color(NamedColor::Gray)
-                    } else {
-                        Marker::new()
-                    });
-                if let Some(total) = total {
-                    trace = trace.hover_info(HoverInfo::Text).hover_text(format!(
-                        "{:.0} {}",
-                        v * total / 100.,
-                        conf.currency
-                    ));
+            let mut missing = Vec::new();
+            let sector = field(sector_col);
+            if !sector.is_empty()
+                && !SECTORS.contains(sector)
+                && !SECTOR_SYNONYMS.contains_key(sector)
+            {
+                missing.push((sector, "Sector"));
+            }
+            let country = field(country_col);
+            if !country.is_empty() {
+                for (map, dimension) in [
+                    (&COUNTRY_TO_REGION, "Region"),
+                    (&COUNTRY_TO_MARKET, "Market"),
+                    (&COUNTRY_TO_CONTINENT, "Continent"),
+                    (&COUNTRY_TO_BLOC, "CurrencyBloc"),
+                ] {
+                    if !map.contains_key(country) {
+                        missing.push((country, dimension));
+                    }
                 }
-                plot.add_trace(trace);
+            }
+            for (label, dimension) in missing {
+                unmapped
+                    .entry((dimension.to_string(), label.to_string()))
+                    .or_default()
+                    .insert(last_isin.clone());
             }
         }
     }
-    plot.set_layout(layout);
-    let output_file = if !conf.output_folder.is_empty() {
-        format!(
-            "{}/{}",
-            conf.output_folder,
-            conf.output_file_name.to_string_lossy()
-        )
-    } else {
-        conf.output_file_name.to_string_lossy().to_string()
+    Ok(unmapped)
+}
+
+/// Print the unmapped labels grouped by the dimension they are missing from
+pub fn print_unmapped(unmapped: &Unmapped) {
+    if unmapped.is_empty() {
+        println!("All sectors and countries are mapped");
+        return;
+    }
+    let mut last_dimension = None;
+    for ((dimension, label), isins) in unmapped {
+        if last_dimension != Some(dimension) {
+            let count = unmapped.keys().filter(|(d, _)| d == dimension).count();
+            println!("Missing {} mapping ({}):", dimension, count);
+            last_dimension = Some(dimension);
+        }
+        println!(
+            "  {} (used by {})",
+            label,
+            isins.iter().cloned().collect::<Vec<_>>().join(", ")
+        );
+    }
+}
+
+/// Parse a single securities CSV file into `securities`. Rows with an empty ISIN belong to the
+/// previous ISIN in the file or, before any ISIN is given, to `fallback_isin`.
+///
+/// The file is streamed through a single reused record buffer and every distinct exposure label
+/// is allocated once in `labels` and shared between securities, so peak memory grows with the
+/// number of securities and distinct labels rather than with the size of the file.
+#[instrument(skip(reader, conf, labels, securities))]
+fn parse_securities_file(
+    file_path: &Path,
+    reader: Box<dyn Read>,
+    fallback_isin: Option<&str>,
+    conf: &Conf,
+    labels: &mut HashSet<Arc<str>>,
+    securities: &mut HashMap<String, Security>,
+) -> Result<(), Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    check_duplicate_headers(&headers, &file_path.to_string_lossy())?;
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let required = |name: &str| {
+        column(name).ok_or(format!(
+            "Missing {} column in {}",
+            name,
+            file_path.display()
+        ))
     };
-    plot.write_html(format!("{}.html", output_file));
-    if conf.image {
-        plot.write_image(
-            format!(
-                "{}.{}",
-                output_file,
-                <ImageFormat as Into<PlotlyImageFormat>>::into(conf.image_format)
-            ),
-            conf.image_format.into(),
-            1920,
-            1080,
-            conf.image_scale,
+    let (isin_col, name_col, ticker_col, ter_col) = (
+        required("ISIN")?,
+        required("Name")?,
+        column("Ticker"),
+        required("TER")?,
+    );
+    let duration_col = column("Duration");
+    let transaction_cost_col = column("TransactionCost");
+    let pe_col = column("PE");
+    let div_growth_col = column("DivGrowth");
+    let payout_ratio_col = column("PayoutRatio");
+    let volatility_col = column("Volatility");
+    let asset_class_col = column("AssetClass");
+    let tag_col = column("Tag");
+    let sec_lending_col = column("SecLendingIncome");
+    let currency_hedge_col = column("CurrencyHedge");
+    let holding_weight_col = required("HoldingWeight")?;
+    // Without a SectorWeight column the Sector of each row is that of its holding, so the sectors
+    // are aggregated from the holding weights
+    let sector_per_holding = column("SectorWeight").is_none();
+    if sector_per_holding {
+        event!(
+            Level::INFO,
+            "No SectorWeight column in {}, weighting the sector of each holding by its weight",
+            file_path.display()
         );
     }
-    if conf.display {
-        plot.show();
+    let mut exposure_cols = vec![
+        (Exposure::Holding, required("Holding")?, holding_weight_col),
+        (
+            Exposure::Sector,
+            required("Sector")?,
+            column("SectorWeight").unwrap_or(holding_weight_col),
+        ),
+        (
+            Exposure::Country,
+            required("Country")?,
+            required("CountryWeight")?,
+        ),
+        (
+            Exposure::Region,
+            required("Region")?,
+            required("RegionWeight")?,
+        ),
+    ];
+    if let (Some(market_col), Some(weight_col)) = (column("Market"), column("MarketWeight")) {
+        exposure_cols.push((Exposure::Market, market_col, weight_col));
+    }
+    let numeric_cols = [
+        Some(ter_col),
+        duration_col,
+        transaction_cost_col,
+        pe_col,
+        div_growth_col,
+        payout_ratio_col,
+        volatility_col,
+        sec_lending_col,
+        currency_hedge_col,
+    ]
+    .into_iter()
+    .flatten()
+    .chain(exposure_cols.iter().map(|&(_, _, weight_col)| weight_col))
+    .collect::<Vec<_>>();
+    let mut last_isin = fallback_isin.unwrap_or_default().to_string();
+    let mut unknown_sectors = HashSet::<String>::new();
+    let mut stripped_cols = HashSet::<usize>::new();
+    // Weight of each label as listed in the file, by ISIN and exposure
+    let mut listed = HashMap::<(String, Exposure, String), f32>::new();
+    let mut record = csv::StringRecord::new();
+    while rdr.read_record(&mut record)? {
+        let field = |idx: usize| record.get(idx).unwrap_or_default();
+        let isin = field(isin_col);
+        if !isin.is_empty() && isin != last_isin {
+            last_isin = isin.to_string();
+        }
+        if !isin.is_empty() || record.position().is_some_and(|pos| pos.record() == 1) {
+            if let Err(err) = validate_isin(&last_isin) {
+                let message = format!(
+                    "Securities ISIN {} in {} on line {}: {}",
+                    last_isin,
+                    file_path.display(),
+                    record.position().map_or(0, |pos| pos.line()),
+                    err
+                );
+                if conf.strict_isin {
+                    return Err(message.into());
+                }
+                event!(Level::WARN, "{}", message);
+            }
+        }
+        // NaN and infinity parse as numbers but would poison every sum they end up in
+        if let Some(&col) = numeric_cols
+            .iter()
+            .find(|&&col| field(col).parse::<f32>().is_ok_and(|v| !v.is_finite()))
+        {
+            return Err(format!(
+                "Securities ISIN {} in {} on line {} has a non-finite {} {:?}",
+                last_isin,
+                file_path.display(),
+                record.position().map_or(0, |pos| pos.line()),
+                &headers[col],
+                field(col)
+            )
+            .into());
+        }
+        let security = match securities.get_mut(&last_isin) {
+            Some(security) => security,
+            None => securities.entry(last_isin.clone()).or_default(),
+        };
+        let name = field(name_col);
+        if !name.is_empty() {
+            security.name = name.to_string();
+        }
+        let ticker = ticker_col.map(field).unwrap_or_default();
+        if !ticker.is_empty() {
+            security.ticker = ticker.to_string();
+        }
+        let ter = field(ter_col).parse::<f32>().unwrap_or(0.);
+        if ter > 0.0 {
+            security.ter = ter;
+        }
+        if let Some(duration) = duration_col.and_then(|col| field(col).parse::<f32>().ok()) {
+            security.duration = Some(duration);
+        }
+        if let Some(cost) = transaction_cost_col.and_then(|col| field(col).parse::<f32>().ok()) {
+            security.transaction_cost = Some(cost);
+        }
+        if let Some(pe) = pe_col.and_then(|col| field(col).parse::<f32>().ok()) {
+            security.pe = Some(pe);
+        }
+        if let Some(growth) = div_growth_col.and_then(|col| field(col).parse::<f32>().ok()) {
+            security.div_growth = Some(growth);
+        }
+        if let Some(ratio) = payout_ratio_col.and_then(|col| field(col).parse::<f32>().ok()) {
+            security.payout_ratio = Some(ratio);
+        }
+        if let Some(volatility) = volatility_col.and_then(|col| field(col).parse::<f32>().ok()) {
+            security.volatility = Some(volatility);
+        }
+        if let Some(income) = sec_lending_col.and_then(|col| field(col).parse::<f32>().ok()) {
+            security.sec_lending_income = Some(income);
+        }
+        if let Some(hedge) = currency_hedge_col.and_then(|col| field(col).parse::<f32>().ok()) {
+            if !(0. ..=100.).contains(&hedge) {
+                return Err(format!(
+                    "Securities ISIN {} in {} has a CurrencyHedge of {}% outside 0-100%",
+                    last_isin,
+                    file_path.display(),
+                    hedge
+                )
+                .into());
+            }
+            security.currency_hedge = Some(hedge / 100.);
+        }
+        let tag = tag_col.map(field).unwrap_or_default();
+        if !tag.is_empty() {
+            let label = match labels.get(tag) {
+                Some(label) => label.clone(),
+                None => {
+                    let label = Arc::<str>::from(tag);
+                    labels.insert(label.clone());
+                    label
+                }
+            };
+            security.tag = Weights::from([(label, 1.)]);
+        }
+        let asset_class = asset_class_col.map(field).unwrap_or_default();
+        if !asset_class.is_empty() {
+            security.fixed_income =
+                FIXED_INCOME_CLASSES.contains(asset_class.to_uppercase().as_str());
+        }
+        for &(exposure, item_col, weight_col) in &exposure_cols {
+            let raw_item = field(item_col);
+            let mut item = raw_item;
+            if exposure == Exposure::Sector && !item.is_empty() && !SECTORS.contains(item) {
+                item = match SECTOR_SYNONYMS.get(item) {
+                    Some(sector) => sector,
+                    None if conf.lenient_sectors => {
+                        if unknown_sectors.insert(item.to_string()) {
+                            event!(
+                                Level::WARN,
+                                "Unknown sector {} for {} assigned to {}",
+                                item,
+                                last_isin,
+                                conf.other_label
+                            );
+                        }
+                        conf.other_label.as_str()
+                    }
+                    None => {
+                        return Err(format!("Unknown sector {} in record {:?}", item, record).into())
+                    }
+                };
+            }
+            let Some((weight, stripped)) = parse_weight(field(weight_col)) else {
+                continue;
+            };
+            if stripped && stripped_cols.insert(weight_col) {
+                event!(
+                    Level::INFO,
+                    "Stripped units from {} values in {}, eg {:?}",
+                    &headers[weight_col],
+                    file_path.display(),
+                    field(weight_col)
+                );
+            }
+            let weight = weight / conf.weight_unit.whole();
+            if weight < 0.0 {
+                event!(
+                    Level::WARN,
+                    "Ignoring negative {} {} of {} for {} in {}",
+                    &headers[weight_col],
+                    field(weight_col),
+                    item,
+                    last_isin,
+                    file_path.display()
+                );
+                continue;
+            }
+            if weight == 0.0 && !conf.keep_zero_weights {
+                continue;
+            }
+            // Holdings without a sector are left to the unknown share
+            if exposure == Exposure::Sector && sector_per_holding && item.is_empty() {
+                continue;
+            }
+            let label = match labels.get(item) {
+                Some(label) => label.clone(),
+                None => {
+                    let label = Arc::<str>::from(item);
+                    labels.insert(label.clone());
+                    label
+                }
+            };
+            // A label listed again replaces its weight, unless the sectors are aggregated per
+            // holding
+            let replaced = if exposure == Exposure::Sector && sector_per_holding {
+                None
+            } else {
+                listed.insert((last_isin.clone(), exposure, raw_item.to_string()), weight)
+            };
+            // Accumulate, as several sectors can map to the same synonym or to "Other"
+            *security
+                .get_exposure_mut(exposure)
+                .entry(label)
+                .or_insert(0.) += weight - replaced.unwrap_or_default();
+        }
     }
     Ok(())
 }
+
+/// Exposure of nested securities expanded with a weight of 1, keyed by ISIN and exposure
+type ExpansionCache = HashMap<(String, Exposure), HashMap<String, f32>>;
+
+/// Add the exposure of `isin` with `base_weight` to `results`, expanding nested securities. The
+/// expansion of `trace_isin` and everything below it is logged at INFO level. Nested securities
+/// are expanded once into `cache` when given. Securities holding each other are an error.
+#[allow(clippy::too_many_arguments)]
+fn calc_exposure(
+    securities: &HashMap<String, Security>,
+    exposure: Exposure,
+    isin: &str,
+    base_weight: f32,
+    partial: PartialHoldings,
+    trace_isin: Option<&str>,
+    cache: Option<&mut ExpansionCache>,
+    results: &mut HashMap<String, f32>,
+) -> Result<(), Box<dyn Error>> {
+    expand_exposure(
+        securities,
+        exposure,
+        isin,
+        base_weight,
+        partial,
+        trace_isin,
+        cache,
+        results,
+        &mut Vec::new(),
+    )
+}
+
+/// `calc_exposure` below the securities in `chain`, the ISINs being expanded
+#[instrument(skip(securities, exposure, results, base_weight, partial, trace_isin, cache, chain), name = "calc", fields(weight=base_weight))]
+#[allow(clippy::too_many_arguments)]
+fn expand_exposure(
+    securities: &HashMap<String, Security>,
+    exposure: Exposure,
+    isin: &str,
+    base_weight: f32,
+    partial: PartialHoldings,
+    trace_isin: Option<&str>,
+    mut cache: Option<&mut ExpansionCache>,
+    results: &mut HashMap<String, f32>,
+    chain: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    event!(Level::TRACE, "Calculating exposure");
+    let security = securities
+        .get(isin)
+        .ok_or(format!("ISIN {} not found in securities", isin))?;
+    if let Some(start) = chain.iter().position(|held| held == isin) {
+        return Err(format!(
+            "Securities hold each other in a cycle: {} -> {}",
+            chain[start..].join(" -> "),
+            isin
+        )
+        .into());
+    }
+    let traced = trace_isin == Some(isin);
+    if traced {
+        event!(
+            Level::INFO,
+            "Expanding {} exposure of {} [{}] with weight {:.4}%",
+            exposure,
+            isin,
+            security.name,
+            base_weight * 100.
+        );
+    }
+    // Tags describe the securities held in the portfolio, not what they hold
+    if exposure == Exposure::Tag {
+        for (tag, weight) in &security.tag {
+            *results.entry(tag.to_string()).or_insert(0.) += weight * base_weight;
+        }
+        return Ok(());
+    }
+    // The sectors of bonds are those of their issuers, which would dilute the equity sectors, so a
+    // fixed income security is reported as a sector of its own
+    if exposure == Exposure::Sector && security.fixed_income {
+        if traced {
+            event!(
+                Level::INFO,
+                "{} {} {}: {:.4}%",
+                isin,
+                exposure,
+                FIXED_INCOME,
+                base_weight * 100.
+            );
+        }
+        *results.entry(FIXED_INCOME.to_string()).or_insert(0.) += base_weight;
+        return Ok(());
+    }
+    // First try to see if any of the holdings is actually an ETF/fund itself that would need expanding
+    chain.push(isin.to_string());
+    let holdings = security.get_exposure(Exposure::Holding);
+    let listed_weight = holdings.values().sum::<f32>();
+    let nested_weight = holdings
+        .iter()
+        .filter(|(holding, _)| securities.contains_key(&***holding))
+        .map(|(_, weight)| weight)
+        .sum::<f32>();
+    // How much the listed holdings and the security's own exposure data are weighted when the
+    // holdings only partially describe a fund of funds
+    let (holding_scale, own_scale) = match partial {
+        PartialHoldings::Scale
+            if nested_weight > 0. && listed_weight > 0. && listed_weight < 1. =>
+        {
+            (1. / listed_weight, 1.)
+        }
+        PartialHoldings::Fallback if nested_weight > 0. && exposure != Exposure::Holding => {
+            (1., (1. - nested_weight).max(0.))
+        }
+        _ => (1., 1.),
+    };
+    for (holding, weight) in holdings {
+        if securities.contains_key(&**holding) {
+            event!(
+                Level::TRACE,
+                "Recursing for holding {}, weight {}",
+                holding,
+                weight * holding_scale
+            );
+            if let Some(cache) = cache.as_deref_mut() {
+                // Expand a nested security once and scale its cached expansion to each parent
+                let key = (holding.to_string(), exposure);
+                if !cache.contains_key(&key) {
+                    let mut expansion = HashMap::new();
+                    expand_exposure(
+                        securities,
+                        exposure,
+                        holding,
+                        1.,
+                        partial,
+                        None,
+                        Some(&mut *cache),
+                        &mut expansion,
+                        chain,
+                    )?;
+                    cache.insert(key.clone(), expansion);
+                }
+                for (label, value) in &cache[&key] {
+                    *results.entry(label.clone()).or_insert(0.) +=
+                        value * base_weight * weight * holding_scale;
+                }
+            } else {
+                expand_exposure(
+                    securities,
+                    exposure,
+                    holding,
+                    base_weight * weight * holding_scale,
+                    partial,
+                    if traced { Some(&**holding) } else { trace_isin },
+                    None,
+                    results,
+                    chain,
+                )?;
+            }
+            event!(
+                Level::DEBUG,
+                "Results after holding {}: {:?}",
+                holding,
+                results
+            );
+        }
+    }
+    chain.pop();
+    let base_weight = if exposure == Exposure::Holding {
+        base_weight * holding_scale
+    } else {
+        base_weight * own_scale
+    };
+    let exposure_items = security.get_exposure(exposure);
+    for (exposure_item, weight) in exposure_items.iter() {
+        if exposure == Exposure::Holding && securities.contains_key(&**exposure_item) {
+            continue;
+        }
+        if traced {
+            event!(
+                Level::INFO,
+                "{} {} {}: {:.4}%",
+                isin,
+                exposure,
+                exposure_item,
+                weight * base_weight * 100.
+            );
+        } else {
+            event!(
+                Level::TRACE,
+                "{} exposure: {}->{}",
+                exposure_item,
+                weight,
+                weight * base_weight
+            );
+        }
+        results
+            .entry(exposure_item.to_string())
+            .and_modify(|v| {
+                *v += weight * base_weight;
+            })
+            .or_insert_with(|| weight * base_weight);
+    }
+    Ok(())
+}
+
+/// Holding exposure of the portfolio reached through fixed income securities. The direct holdings
+/// of the other securities are merged into a placeholder of the same weight, so nested and partial
+/// holdings are scaled exactly as in the full Holding exposure.
+pub fn fixed_income_holdings(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    options: AnalysisOptions,
+) -> Result<Vec<(String, f32)>, Box<dyn Error>> {
+    const EQUITY: &str = "\0Equity";
+    let equity = Arc::<str>::from(EQUITY);
+    let view = securities
+        .iter()
+        .map(|(isin, security)| {
+            let mut holding = Weights::new();
+            for (label, weight) in &security.holding {
+                let label = if security.fixed_income || securities.contains_key(&**label) {
+                    label.clone()
+                } else {
+                    equity.clone()
+                };
+                *holding.entry(label).or_insert(0.) += weight;
+            }
+            let security = Security {
+                holding,
+                fixed_income: security.fixed_income,
+                ..Default::default()
+            };
+            (isin.clone(), security)
+        })
+        .collect::<HashMap<_, _>>();
+    let mut results = analyze_exposure(&view, portfolio, Exposure::Holding, options)?;
+    results.retain(|(label, _)| label != EQUITY && label != options.unknown_label);
+    if results.is_empty() {
+        event!(
+            Level::INFO,
+            "No holdings of fixed income securities, not splitting the Holding exposure"
+        );
+    }
+    Ok(results)
+}
+
+/// Longest chain of securities holding each other, starting from `isin`. Holdings already in the
+/// chain are skipped so a cycle in the data can't recurse forever. Holdings are walked in ISIN
+/// order, so the first of equally deep chains is reported, and the chain below each security is
+/// only walked once.
+fn deepest_chain<'a>(securities: &'a HashMap<String, Security>, isin: &'a str) -> Vec<&'a str> {
+    fn walk<'a>(
+        securities: &'a HashMap<String, Security>,
+        isin: &'a str,
+        chain: &mut Vec<&'a str>,
+        below: &mut HashMap<&'a str, Vec<&'a str>>,
+    ) -> Vec<&'a str> {
+        if let Some(deepest) = below.get(isin) {
+            return deepest.clone();
+        }
+        let mut deepest = vec![isin];
+        if let Some(security) = securities.get(isin) {
+            let mut holdings = security
+                .holding
+                .keys()
+                .map(|holding| &**holding)
+                .filter(|holding| securities.contains_key(*holding) && !chain.contains(holding))
+                .collect::<Vec<_>>();
+            holdings.sort();
+            chain.push(isin);
+            for holding in holdings {
+                let nested = walk(securities, holding, chain, below);
+                if nested.len() >= deepest.len() {
+                    deepest = [isin].into_iter().chain(nested).collect();
+                }
+            }
+            chain.pop();
+        }
+        below.insert(isin, deepest.clone());
+        deepest
+    }
+    walk(securities, isin, &mut Vec::new(), &mut HashMap::new())
+}
+
+/// Log the deepest fund of funds nesting of the portfolio and the chain of securities behind it
+pub fn report_nesting(securities: &HashMap<String, Security>, portfolio: &HashMap<String, f32>) {
+    let mut isins = portfolio.keys().collect::<Vec<_>>();
+    isins.sort();
+    let Some(chain) = isins
+        .into_iter()
+        .map(|isin| deepest_chain(securities, isin))
+        .reduce(|deepest, chain| {
+            if chain.len() > deepest.len() {
+                chain
+            } else {
+                deepest
+            }
+        })
+    else {
+        return;
+    };
+    event!(
+        Level::INFO,
+        "Lookthrough depth {}: {}",
+        chain.len(),
+        chain.join(" -> ")
+    );
+}
+
+/// Compare the stated sectors of a fund with the sectors of its holdings looked through, in
+/// percentage points. `None` unless the fund has sectors and holdings covering at least half of it.
+fn sector_divergence(
+    securities: &HashMap<String, Security>,
+    security: &Security,
+    options: AnalysisOptions,
+) -> Result<Option<SectorDivergence>, Box<dyn Error>> {
+    let stated = security.sector.values().sum::<f32>();
+    let nested = security
+        .holding
+        .iter()
+        .filter(|(holding, _)| securities.contains_key(&***holding))
+        .collect::<Vec<_>>();
+    let covered = nested.iter().map(|(_, weight)| **weight).sum::<f32>();
+    if security.fixed_income || stated <= 0. || covered < 0.5 {
+        return Ok(None);
+    }
+    let mut lookthrough = HashMap::new();
+    for (holding, weight) in nested {
+        calc_exposure(
+            securities,
+            Exposure::Sector,
+            holding,
+            *weight,
+            options.partial_holdings,
+            None,
+            None,
+            &mut lookthrough,
+        )?;
+    }
+    let looked_through = lookthrough.values().sum::<f32>();
+    if looked_through <= 0. {
+        return Ok(None);
+    }
+    let mut gaps = security
+        .sector
+        .keys()
+        .map(|sector| sector.to_string())
+        .chain(lookthrough.keys().cloned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|sector| {
+            let own = security
+                .sector
+                .get(sector.as_str())
+                .copied()
+                .unwrap_or_default()
+                / stated;
+            let through = lookthrough.get(&sector).copied().unwrap_or_default() / looked_through;
+            (sector, own * 100., through * 100.)
+        })
+        .collect::<Vec<_>>();
+    let divergence = gaps
+        .iter()
+        .map(|(_, own, through)| (own - through).abs())
+        .sum::<f32>()
+        / 2.;
+    gaps.sort_by(|a, b| (b.1 - b.2).abs().total_cmp(&(a.1 - a.2).abs()));
+    Ok(Some((divergence, covered, gaps.swap_remove(0))))
+}
+
+/// Warn about the funds of the portfolio whose stated sectors diverge by more than
+/// `max_divergence` percentage points from the sectors of their holdings looked through, which
+/// usually means one of the two is stale or partial. The divergence is the share of the sector
+/// mix that would have to move for the two to match.
+pub fn check_sector_consistency(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    options: AnalysisOptions,
+    max_divergence: f32,
+) -> Result<(), Box<dyn Error>> {
+    let mut funds = BTreeSet::new();
+    let mut pending = portfolio.keys().cloned().collect::<Vec<_>>();
+    while let Some(isin) = pending.pop() {
+        let Some(security) = securities.get(&isin) else {
+            continue;
+        };
+        if funds.insert(isin) {
+            pending.extend(
+                security
+                    .holding
+                    .keys()
+                    .filter(|holding| securities.contains_key(&***holding))
+                    .map(|holding| holding.to_string()),
+            );
+        }
+    }
+    for isin in funds {
+        let security = &securities[&isin];
+        let Some((divergence, covered, (sector, own, through))) =
+            sector_divergence(securities, security, options)?
+        else {
+            continue;
+        };
+        if divergence > max_divergence {
+            event!(
+                Level::WARN,
+                "Sectors of {} [{}] diverge by {:.1} points from its holdings looked through, which cover {:.0}% of it. Largest gap: {} {:.1}% stated vs {:.1}% looked through",
+                isin,
+                security.name,
+                divergence,
+                covered * 100.,
+                sector,
+                own,
+                through
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn analyze_exposure(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    exposure: Exposure,
+    options: AnalysisOptions,
+) -> Result<Vec<(String, f32)>, Box<dyn Error>> {
+    let mut results: HashMap<String, f32> = HashMap::new();
+    let mut errors = Vec::new();
+    // Tracing logs every step of the expansion, so it bypasses the cache
+    let mut cache = options.trace_isin.is_none().then(ExpansionCache::new);
+    for (isin, weight) in portfolio {
+        let mut isin_results: HashMap<String, f32> = HashMap::new();
+        let result = calc_exposure(
+            securities,
+            exposure,
+            isin,
+            *weight,
+            options.partial_holdings,
+            options.trace_isin,
+            cache.as_mut(),
+            &mut isin_results,
+        );
+        match result {
+            Ok(_) if isin_results.values().any(|v| !v.is_finite()) => {
+                errors.push(format!(
+                    "ISIN {} has a non-finite {} exposure: {:?}",
+                    isin, exposure, isin_results
+                ));
+            }
+            Ok(_) => {
+                event!(Level::DEBUG, "Results for {}: {:?}", isin, isin_results);
+                for (key, val) in isin_results.into_iter() {
+                    results
+                        .entry(key.clone())
+                        .and_modify(|share| {
+                            event!(
+                                Level::TRACE,
+                                "Modifying {}: {}->{}",
+                                key,
+                                *share,
+                                *share + val
+                            );
+                            *share += val
+                        })
+                        .or_insert_with(|| val);
+                }
+            }
+            Err(err) => {
+                errors.push(err.to_string());
+            }
+        }
+    }
+    if !errors.is_empty() {
+        for err in &errors {
+            error!("{}", err);
+        }
+        return Err(format!("{} errors in the {} exposure", errors.len(), exposure).into());
+    }
+    let mut results = results
+        .into_iter()
+        .map(|(k, v)| (k, v * 100.))
+        .collect::<Vec<_>>();
+    let mut total = results.iter().fold(0., |acc, (_, v)| acc + *v);
+    if let Some(epsilon) = options.round_epsilon {
+        total = reconcile_total(&mut results, total, epsilon);
+    }
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    if total < 100. {
+        results.push((options.unknown_label.to_string(), 100. - total));
+    } else if total > 100. + TOTAL_NOISE {
+        return Err(format!(
+            "Total {} exposure {}% > 100%, see --round-to-100 for rounding errors",
+            exposure, total
+        )
+        .into());
+    }
+    event!(Level::DEBUG, "Analysis results: {:?}", results);
+    Ok(results)
+}
+
+/// Spread a residual within `epsilon` of 100% proportionally over all entries so the
+/// total reads exactly 100%. Returns the new total.
+fn reconcile_total(results: &mut [(String, f32)], total: f32, epsilon: f32) -> f32 {
+    let residual = 100. - total;
+    if residual == 0. || residual.abs() > epsilon || total <= 0. {
+        return total;
+    }
+    event!(
+        Level::DEBUG,
+        "Reconciling total {}% by {}%",
+        total,
+        residual
+    );
+    for (_, v) in results.iter_mut() {
+        *v += residual * *v / total;
+    }
+    100.
+}
+
+#[instrument(skip_all, name = "calc")]
+pub fn calculate_ter(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+) -> Result<f32, Box<dyn Error>> {
+    let mut ter = 0.0;
+    for (isin, weight) in sorted_positions(portfolio) {
+        let security = costed_security(securities, isin, weight)?;
+        if !(0. ..=MAX_PLAUSIBLE_TER).contains(&security.ter) {
+            event!(
+                Level::WARN,
+                "ISIN {} has an implausible TER of {}%",
+                isin,
+                security.ter
+            );
+        }
+        ter += security.ter * weight;
+    }
+    event!(Level::INFO, "Calculated portfolio TER: {:.3}%", ter);
+    Ok(ter)
+}
+
+/// The portfolio positions in ISIN order, so that sums over them don't depend on the hash map
+/// iteration order
+fn sorted_positions(portfolio: &HashMap<String, f32>) -> Vec<(&String, f32)> {
+    let mut positions = portfolio
+        .iter()
+        .map(|(isin, weight)| (isin, *weight))
+        .collect::<Vec<_>>();
+    positions.sort_by(|a, b| a.0.cmp(b.0));
+    positions
+}
+
+/// The security of a portfolio position whose TER is summed, with a finite TER and weight
+fn costed_security<'a>(
+    securities: &'a HashMap<String, Security>,
+    isin: &str,
+    weight: f32,
+) -> Result<&'a Security, Box<dyn Error>> {
+    let security = securities
+        .get(isin)
+        .ok_or(format!("ISIN {} not found in securities", isin))?;
+    if !security.ter.is_finite() {
+        return Err(format!("ISIN {} has a non-finite TER {}", isin, security.ter).into());
+    }
+    if !weight.is_finite() {
+        return Err(format!("ISIN {} has a non-finite weight {}", isin, weight).into());
+    }
+    Ok(security)
+}
+
+/// The `name` metric `value` of a security, left out with a warning when it isn't finite
+fn finite_metric(isin: &str, name: &str, value: Option<f32>) -> Option<f32> {
+    match value {
+        Some(value) if !value.is_finite() => {
+            event!(
+                Level::WARN,
+                "ISIN {} has a non-finite {} {}, leaving it out",
+                isin,
+                name,
+                value
+            );
+            None
+        }
+        value => value,
+    }
+}
+
+/// Print the estimated annual fee of every portfolio security and of the whole portfolio in
+/// currency, based on the TER and the portfolio total value
+pub fn print_ter(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    total: f32,
+    currency: &str,
+) {
+    let mut fees = portfolio
+        .iter()
+        .filter_map(|(isin, weight)| {
+            let security = securities.get(isin)?;
+            Some((isin, security, weight * total))
+        })
+        .collect::<Vec<_>>();
+    fees.sort_by(|a, b| (b.1.ter * b.2).total_cmp(&(a.1.ter * a.2)));
+    println!(
+        "{:<14} {:<40} {:>7} {:>14} {:>14}",
+        "ISIN", "Name", "TER", "Amount", "Annual cost"
+    );
+    let mut cost = 0.;
+    for (isin, security, amount) in fees {
+        let fee = security.ter * amount / 100.;
+        cost += fee;
+        println!(
+            "{:<14} {:<40.40} {:>6.3}% {:>12.2} {} {:>12.2} {}",
+            isin, security.name, security.ter, amount, currency, fee, currency
+        );
+    }
+    println!("Estimated annual cost: {:.2} {}", cost, currency);
+}
+
+/// Calculate the weighted net TER of the portfolio as TER minus securities lending income, or
+/// `None` if none of the securities has lending income
+#[instrument(skip_all, name = "calc")]
+pub fn calculate_net_ter(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+) -> Result<Option<f32>, Box<dyn Error>> {
+    let mut ter = 0.0;
+    let mut has_income = false;
+    for (isin, weight) in sorted_positions(portfolio) {
+        let security = costed_security(securities, isin, weight)?;
+        let income = finite_metric(
+            isin,
+            "securities lending income",
+            security.sec_lending_income,
+        );
+        has_income |= income.is_some();
+        ter += (security.ter - income.unwrap_or_default()) * weight;
+    }
+    if !has_income {
+        return Ok(None);
+    }
+    event!(Level::INFO, "Calculated portfolio net TER: {:.3}%", ter);
+    Ok(Some(ter))
+}
+
+/// Calculate the weighted total cost of the portfolio as TER plus transaction costs, or `None` if
+/// none of the securities has a transaction cost
+#[instrument(skip_all, name = "calc")]
+pub fn calculate_total_cost(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+) -> Result<Option<f32>, Box<dyn Error>> {
+    let mut cost = 0.0;
+    let mut has_transaction_cost = false;
+    for (isin, weight) in sorted_positions(portfolio) {
+        let security = costed_security(securities, isin, weight)?;
+        let transaction_cost = finite_metric(isin, "transaction cost", security.transaction_cost);
+        has_transaction_cost |= transaction_cost.is_some();
+        cost += (security.ter + transaction_cost.unwrap_or_default()) * weight;
+    }
+    if !has_transaction_cost {
+        return Ok(None);
+    }
+    event!(Level::INFO, "Calculated portfolio total cost: {:.3}%", cost);
+    Ok(Some(cost))
+}
+
+/// Calculate the portfolio P/E as the weighted harmonic mean of the P/E of the securities that
+/// have a positive one, which weights them by earnings. `None` if none of them has one.
+#[instrument(skip_all, name = "calc")]
+pub fn calculate_pe(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+) -> Result<Option<f32>, Box<dyn Error>> {
+    let mut earnings_yield = 0.0;
+    let mut covered = 0.0;
+    for (isin, weight) in sorted_positions(portfolio) {
+        let security = securities
+            .get(isin)
+            .ok_or(format!("ISIN {} not found in securities", isin))?;
+        // A P/E of zero would be an infinite earnings yield
+        if let Some(pe) = finite_metric(isin, "P/E", security.pe).filter(|pe| *pe > 0.) {
+            earnings_yield += weight / pe;
+            covered += weight;
+        }
+    }
+    if earnings_yield <= 0. {
+        return Ok(None);
+    }
+    let pe = covered / earnings_yield;
+    event!(
+        Level::INFO,
+        "Calculated portfolio P/E: {:.1} covering {:.2}% of the portfolio",
+        pe,
+        covered * 100.
+    );
+    Ok(Some(pe))
+}
+
+/// Expand every portfolio security into its underlying holdings. Returns `(ISIN, distinct
+/// holdings, covered weight %, HHI)` sorted by ISIN, with the HHI as in `hhi`.
+pub fn calculate_lookthrough(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    options: AnalysisOptions,
+) -> Result<Vec<Lookthrough>, Box<dyn Error>> {
+    let mut isins = portfolio.keys().collect::<Vec<_>>();
+    isins.sort();
+    let mut lookthrough = Vec::new();
+    for isin in isins {
+        let mut results = HashMap::new();
+        calc_exposure(
+            securities,
+            Exposure::Holding,
+            isin,
+            1.,
+            options.partial_holdings,
+            options.trace_isin,
+            None,
+            &mut results,
+        )?;
+        let results = results
+            .into_iter()
+            .map(|(k, v)| (k, v * 100.))
+            .collect::<Vec<_>>();
+        let covered = results.iter().map(|(_, v)| v).sum::<f32>();
+        lookthrough.push((
+            isin.clone(),
+            results.len(),
+            covered,
+            hhi(&results, options.unknown_label),
+        ));
+    }
+    Ok(lookthrough)
+}
+
+/// Print the lookthrough from `calculate_lookthrough` as a table
+pub fn print_lookthrough(securities: &HashMap<String, Security>, lookthrough: &[Lookthrough]) {
+    println!(
+        "{:<14} {:<40} {:>8} {:>8} {:>8}",
+        "ISIN", "Name", "Holdings", "Covered", "HHI"
+    );
+    for (isin, count, covered, hhi) in lookthrough {
+        let name = securities
+            .get(isin)
+            .map(|security| security.name.as_str())
+            .unwrap_or_default();
+        println!(
+            "{:<14} {:<40.40} {:>8} {:>8} {:>8.0}",
+            isin,
+            name,
+            count,
+            format_percent(*covered),
+            hhi
+        );
+    }
+}
+
+/// Print the largest underlying holdings from the holding exposure as a numbered table with
+/// cumulative weights
+pub fn print_top_holdings(holdings: &[(String, f32)], count: usize, unknown: &str) {
+    println!(
+        "{:>4} {:<40} {:>8} {:>10}",
+        "#", "Holding", "Weight", "Cumulative"
+    );
+    let mut cumulative = 0.;
+    for (idx, (holding, weight)) in holdings
+        .iter()
+        .filter(|(holding, _)| holding != unknown)
+        .take(count)
+        .enumerate()
+    {
+        cumulative += weight;
+        println!(
+            "{:>4} {:<40.40} {:>8} {:>10}",
+            idx + 1,
+            holding,
+            format_percent(*weight),
+            format_percent(cumulative)
+        );
+    }
+}
+
+/// Whether a holding looks like a fund by its name, ie one of its words is a fund keyword. An ISIN
+/// alone says nothing, as stocks have ISINs too.
+fn looks_like_fund(holding: &str) -> bool {
+    holding
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| FUND_KEYWORDS.contains(word.to_uppercase().as_str()))
+}
+
+/// Print the holdings that look like funds but are missing from the securities, so they could not
+/// be expanded, with their weight in the portfolio
+pub fn print_unexpanded_funds(holdings: &[(String, f32)], unknown: &str) {
+    let funds = holdings
+        .iter()
+        .filter(|(holding, _)| holding != unknown && looks_like_fund(holding))
+        .collect::<Vec<_>>();
+    if funds.is_empty() {
+        println!("No unexpanded funds found");
+        return;
+    }
+    println!("{:<40} {:>8}", "Unexpanded fund", "Weight");
+    for (holding, weight) in funds {
+        println!("{:<40.40} {:>8}", holding, format_percent(*weight));
+    }
+}
+
+/// Contribution in percent of each portfolio security to a label of an exposure, largest first
+pub fn contributions(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    exposure: Exposure,
+    label: &str,
+    conf: &Conf,
+) -> Result<Vec<(String, f32)>, Box<dyn Error>> {
+    let mut contributions = Vec::new();
+    for (isin, weight) in portfolio {
+        let mut results = HashMap::new();
+        calc_exposure(
+            securities,
+            exposure,
+            isin,
+            *weight,
+            conf.partial_holdings,
+            None,
+            None,
+            &mut results,
+        )?;
+        let contribution = if label == conf.unknown_label {
+            (*weight - results.values().sum::<f32>()).max(0.)
+        } else {
+            results.get(label).copied().unwrap_or_default()
+        };
+        if contribution * 100. >= 0.005 {
+            contributions.push((isin.clone(), contribution * 100.));
+        }
+    }
+    contributions.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(contributions)
+}
+
+/// Interactively browse the ranked results of every exposure in a terminal UI and drill into the
+/// portfolio securities contributing to a label
+#[cfg(feature = "tui")]
+pub fn browse(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    data: &[(Exposure, Vec<(String, f32)>)],
+    conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    use ratatui::{
+        crossterm::event::{read, Event as TerminalEvent, KeyCode, KeyEventKind},
+        layout::Constraint,
+        style::{Modifier, Style},
+        widgets::{Block, Row, Table, TableState},
+    };
+
+    /// A table on the drill-down path: the exposures, the labels of one of them or the
+    /// securities behind one label
+    struct Level {
+        title: String,
+        header: [&'static str; 3],
+        rows: Vec<[String; 3]>,
+        state: TableState,
+    }
+
+    let level = |title: String, header, rows| Level {
+        title,
+        header,
+        rows,
+        state: TableState::default().with_selected(Some(0)),
+    };
+    let mut levels = vec![level(
+        "Exposures".to_string(),
+        ["#", "Exposure", "Labels"],
+        data.iter()
+            .enumerate()
+            .map(|(idx, (exposure, results))| {
+                [
+                    (idx + 1).to_string(),
+                    exposure.to_string(),
+                    results.len().to_string(),
+                ]
+            })
+            .collect(),
+    )];
+    let mut terminal =
+        ratatui::try_init().map_err(|err| format!("Failed to open the terminal UI: {}", err))?;
+    let mut run = || -> Result<(), Box<dyn Error>> {
+        loop {
+            let current = levels.last_mut().expect("the exposures are never closed");
+            terminal.draw(|frame| {
+                let table = Table::new(
+                    current.rows.iter().map(|row| Row::new(row.clone())),
+                    [
+                        Constraint::Length(14),
+                        Constraint::Fill(1),
+                        Constraint::Length(10),
+                    ],
+                )
+                .header(Row::new(current.header).style(Style::new().add_modifier(Modifier::BOLD)))
+                .block(
+                    Block::bordered()
+                        .title(current.title.as_str())
+                        .title_bottom("↑↓ select, Enter drill down, Esc back, q quit"),
+                )
+                .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+                frame.render_stateful_widget(table, frame.area(), &mut current.state);
+            })?;
+            let TerminalEvent::Key(key) = read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Esc | KeyCode::Backspace | KeyCode::Left => {
+                    if levels.len() == 1 {
+                        return Ok(());
+                    }
+                    levels.pop();
+                }
+                KeyCode::Down | KeyCode::Char('j') => current.state.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => current.state.select_previous(),
+                KeyCode::Enter | KeyCode::Right => {
+                    // The selection is kept past the last row by select_next
+                    let selected = |level: &Level| {
+                        level
+                            .state
+                            .selected()
+                            .map(|selected| selected.min(level.rows.len().saturating_sub(1)))
+                    };
+                    let Some((exposure, results)) =
+                        selected(&levels[0]).and_then(|idx| data.get(idx))
+                    else {
+                        continue;
+                    };
+                    match levels.len() {
+                        1 => levels.push(level(
+                            exposure.to_string(),
+                            ["#", "Label", "Weight"],
+                            results
+                                .iter()
+                                .enumerate()
+                                .map(|(idx, (label, weight))| {
+                                    [
+                                        (idx + 1).to_string(),
+                                        label.clone(),
+                                        format_percent(*weight),
+                                    ]
+                                })
+                                .collect(),
+                        )),
+                        2 => {
+                            let Some((label, _)) =
+                                selected(&levels[1]).and_then(|idx| results.get(idx))
+                            else {
+                                continue;
+                            };
+                            let rows =
+                                contributions(securities, portfolio, *exposure, label, conf)?
+                                    .into_iter()
+                                    .map(|(isin, weight)| {
+                                        let name = securities
+                                            .get(&isin)
+                                            .map(|security| security.name.clone())
+                                            .unwrap_or_default();
+                                        [isin, name, format_percent(weight)]
+                                    })
+                                    .collect();
+                            levels.push(level(
+                                format!("{}: {}", exposure, label),
+                                ["ISIN", "Name", "Weight"],
+                                rows,
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    };
+    let result = run();
+    ratatui::restore();
+    result
+}
+
+#[cfg(not(feature = "tui"))]
+pub fn browse(
+    _securities: &HashMap<String, Security>,
+    _portfolio: &HashMap<String, f32>,
+    _data: &[(Exposure, Vec<(String, f32)>)],
+    _conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    Err("Browsing the results in the terminal requires building with the tui feature".into())
+}
+
+/// Weighted average of the `name` metric over the portfolio securities that have a finite one,
+/// together with the share of the portfolio they cover. `None` if none of them has one.
+fn covered_average(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    name: &str,
+    metric: impl Fn(&Security) -> Option<f32>,
+) -> Result<Option<(f32, f32)>, Box<dyn Error>> {
+    let mut total = 0.0;
+    let mut covered = 0.0;
+    for (isin, weight) in sorted_positions(portfolio) {
+        let security = securities
+            .get(isin)
+            .ok_or(format!("ISIN {} not found in securities", isin))?;
+        if let Some(value) = finite_metric(isin, name, metric(security)) {
+            total += value * weight;
+            covered += weight;
+        }
+    }
+    Ok((covered > 0.).then(|| (total / covered, covered)))
+}
+
+/// Calculate the weighted average duration of the portfolio securities that have a duration, or
+/// `None` if none of them has one
+#[instrument(skip_all, name = "calc")]
+pub fn calculate_duration(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+) -> Result<Option<f32>, Box<dyn Error>> {
+    let Some((duration, covered)) =
+        covered_average(securities, portfolio, "duration", |security| {
+            security.duration
+        })?
+    else {
+        return Ok(None);
+    };
+    event!(
+        Level::INFO,
+        "Calculated duration {:.2} years over {:.2}% of the portfolio",
+        duration,
+        covered * 100.
+    );
+    Ok(Some(duration))
+}
+
+/// Calculate the weighted average dividend growth rate of the portfolio securities that have one,
+/// or `None` if none of them has one
+#[instrument(skip_all, name = "calc")]
+pub fn calculate_div_growth(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+) -> Result<Option<f32>, Box<dyn Error>> {
+    let Some((growth, covered)) =
+        covered_average(securities, portfolio, "dividend growth", |security| {
+            security.div_growth
+        })?
+    else {
+        return Ok(None);
+    };
+    event!(
+        Level::INFO,
+        "Calculated dividend growth {:.2}% over {:.2}% of the portfolio",
+        growth,
+        covered * 100.
+    );
+    Ok(Some(growth))
+}
+
+/// Calculate the weighted average payout ratio of the portfolio securities that have one, or
+/// `None` if none of them has one
+#[instrument(skip_all, name = "calc")]
+pub fn calculate_payout_ratio(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+) -> Result<Option<f32>, Box<dyn Error>> {
+    let Some((ratio, covered)) =
+        covered_average(securities, portfolio, "payout ratio", |security| {
+            security.payout_ratio
+        })?
+    else {
+        return Ok(None);
+    };
+    event!(
+        Level::INFO,
+        "Calculated payout ratio {:.1}% over {:.2}% of the portfolio",
+        ratio,
+        covered * 100.
+    );
+    Ok(Some(ratio))
+}
+
+/// Calculate the trades needed to move the current portfolio to the `target` weights. Returns
+/// `(ISIN, current amount, target weight, trade amount)` sorted by ISIN, where a positive trade
+/// amount is a buy and a negative one a sell.
+///
+/// With `no_sell` the positions are only topped up: the target part of the portfolio grows to the
+/// smallest value at which no position exceeds its target weight and positions missing from the
+/// targets are left untouched.
+#[instrument(skip_all, name = "rebalance")]
+pub fn calculate_rebalance(
+    portfolio: &HashMap<String, f32>,
+    total: f32,
+    target: &HashMap<String, f32>,
+    no_sell: bool,
+) -> Vec<(String, f32, f32, f32)> {
+    let current = |isin: &str| portfolio.get(isin).map_or(0., |weight| weight * total);
+    let target_total = if no_sell {
+        target
+            .iter()
+            .filter(|(_, weight)| **weight > 0.)
+            .map(|(isin, weight)| current(isin) / weight)
+            .fold(
+                target.keys().map(|isin| current(isin)).sum::<f32>(),
+                f32::max,
+            )
+    } else {
+        total
+    };
+    event!(
+        Level::DEBUG,
+        "Rebalancing to target value {:.2}",
+        target_total
+    );
+    let isins = portfolio
+        .keys()
+        .chain(target.keys())
+        .collect::<BTreeSet<_>>();
+    isins
+        .into_iter()
+        .map(|isin| {
+            let amount = current(isin);
+            let (weight, trade) = match target.get(isin) {
+                Some(weight) => (*weight, weight * target_total - amount),
+                None => (0., -amount),
+            };
+            // Positions above their target, eg with a target of 0, are kept when not selling
+            let trade = if no_sell { trade.max(0.) } else { trade };
+            (isin.clone(), amount, weight, trade)
+        })
+        .collect()
+}
+
+/// Print the trades from `calculate_rebalance` as a table
+pub fn print_rebalance(
+    securities: &HashMap<String, Security>,
+    trades: &[(String, f32, f32, f32)],
+    currency: &str,
+) {
+    println!(
+        "{:<14} {:<40} {:>14} {:>8} {:>14}",
+        "ISIN", "Name", "Current", "Target", "Trade"
+    );
+    for (isin, amount, weight, trade) in trades {
+        let name = securities
+            .get(isin)
+            .map(|security| security.name.as_str())
+            .unwrap_or_default();
+        let trade = if trade.abs() < 0.005 { 0. } else { *trade };
+        println!(
+            "{:<14} {:<40.40} {:>12.2} {} {:>8} {:>+12.2} {}",
+            isin,
+            name,
+            amount,
+            currency,
+            format_percent(weight * 100.),
+            trade,
+            currency
+        );
+    }
+    let cash = trades.iter().map(|(_, _, _, trade)| trade).sum::<f32>();
+    if cash > 0.005 {
+        println!("Additional cash needed: {:.2} {}", cash, currency);
+    }
+}
+
+/// Print the exposures of two portfolios side by side, with the labels that changed the most first
+pub fn print_comparison(
+    names: (&str, &str),
+    data: &[(Exposure, Vec<(String, f32)>)],
+    other: &[(Exposure, Vec<(String, f32)>)],
+    limit: usize,
+    threshold: f32,
+) {
+    println!("A: {}\nB: {}\n", names.0, names.1);
+    for ((exposure, results), (_, other_results)) in data.iter().zip(other) {
+        let mut labels = BTreeMap::<&str, (f32, f32)>::new();
+        for (label, weight) in results {
+            labels.entry(label).or_default().0 = *weight;
+        }
+        for (label, weight) in other_results {
+            labels.entry(label).or_default().1 = *weight;
+        }
+        let mut rows = labels
+            .into_iter()
+            .filter(|(_, (weight, other_weight))| (other_weight - weight).abs() >= threshold)
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| (b.1 .1 - b.1 .0).abs().total_cmp(&(a.1 .1 - a.1 .0).abs()));
+        println!("{:<40} {:>10} {:>10} {:>8}", exposure, "A", "B", "Change");
+        if rows.is_empty() {
+            println!("No change of at least {} percentage points", threshold);
+        }
+        for (label, (weight, other_weight)) in rows.into_iter().take(limit) {
+            let change = other_weight - weight;
+            let change = if change.abs() < 0.005 { 0. } else { change };
+            println!(
+                "{:<40.40} {:>10} {:>10} {:>+7.2}%",
+                label,
+                format_percent(weight),
+                format_percent(other_weight),
+                change
+            );
+        }
+        println!();
+    }
+}
+
+/// Parse policy rules from a CSV file in the `Exposure,Label,Comparator,Threshold` format, where
+/// exposure is one of the exposures or `TER` and threshold is a percentage
+#[instrument(skip(file_path))]
+pub fn parse_rules(file_path: &str) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut rules = Vec::new();
+    let mut rdr = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(file);
+    for result in rdr.deserialize() {
+        let record: Record = result?;
+        let field = |name: &str| {
+            record
+                .get(name)
+                .map(String::as_str)
+                .ok_or(format!("Rule {:?} is missing {}", record, name))
+        };
+        let exposure = match field("Exposure")? {
+            ter if ter.eq_ignore_ascii_case("TER") => None,
+            exposure => Some(
+                exposure
+                    .parse::<Exposure>()
+                    .map_err(|_| format!("Unknown exposure {} in rule {:?}", exposure, record))?,
+            ),
+        };
+        let comparator = field("Comparator")?
+            .parse::<Comparator>()
+            .map_err(|_| format!("Unknown comparator in rule {:?}", record))?;
+        let threshold = field("Threshold")?
+            .trim_end_matches('%')
+            .parse::<f32>()
+            .map_err(|_| format!("Bad threshold in rule {:?}", record))?;
+        rules.push(Rule {
+            exposure,
+            label: field("Label").unwrap_or_default().to_string(),
+            comparator,
+            threshold,
+        });
+    }
+    event!(Level::INFO, "Parsed {} rules", rules.len());
+    Ok(rules)
+}
+
+/// Parse target bands from a CSV file in the `Exposure,Label,Min,Max` format, where min and max
+/// are percentages
+#[instrument(skip(file_path))]
+pub fn parse_bands(file_path: &str) -> Result<Vec<Band>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut bands = Vec::new();
+    let mut rdr = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(file);
+    for result in rdr.deserialize() {
+        let record: Record = result?;
+        let field = |name: &str| {
+            record
+                .get(name)
+                .map(String::as_str)
+                .ok_or(format!("Band {:?} is missing {}", record, name))
+        };
+        let percent = |name: &str| -> Result<f32, Box<dyn Error>> {
+            Ok(parse_number(field(name)?).ok_or(format!("Bad {} in band {:?}", name, record))?)
+        };
+        let exposure = field("Exposure")?;
+        let band = Band {
+            exposure: exposure
+                .parse::<Exposure>()
+                .map_err(|_| format!("Unknown exposure {} in band {:?}", exposure, record))?,
+            label: field("Label")?.to_string(),
+            min: percent("Min")?,
+            max: percent("Max")?,
+        };
+        if band.min > band.max {
+            return Err(format!("Band {:?} has min > max", record).into());
+        }
+        bands.push(band);
+    }
+    event!(Level::INFO, "Parsed {} bands", bands.len());
+    Ok(bands)
+}
+
+/// Tickers to label holdings with, from the names of the securities that have a ticker and from an
+/// optional CSV file in the `Holding,Ticker` format, which takes precedence
+#[instrument(skip(securities))]
+pub fn holding_tickers(
+    securities: &HashMap<String, Security>,
+    file_path: Option<&str>,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut tickers = securities
+        .values()
+        .filter(|security| !security.ticker.is_empty() && !security.name.is_empty())
+        .map(|security| (security.name.clone(), security.ticker.clone()))
+        .collect::<HashMap<_, _>>();
+    if let Some(file_path) = file_path {
+        let file = File::open(file_path)?;
+        let mut rdr = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .from_reader(file);
+        for result in rdr.deserialize() {
+            let record: Record = result?;
+            let (Some(holding), Some(ticker)) = (record.get("Holding"), record.get("Ticker"))
+            else {
+                return Err(format!("Ticker {:?} is missing Holding or Ticker", record).into());
+            };
+            if !ticker.is_empty() {
+                tickers.insert(holding.clone(), ticker.clone());
+            }
+        }
+    }
+    event!(Level::INFO, "Parsed {} holding tickers", tickers.len());
+    Ok(tickers)
+}
+
+/// Parse the issuers of holdings from a CSV file in the `Holding,Issuer` format, where holding is
+/// the holding name or ISIN as in the securities file
+#[instrument(skip(file_path))]
+pub fn parse_issuers(file_path: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut issuers = HashMap::new();
+    let mut rdr = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(file);
+    for result in rdr.deserialize() {
+        let record: Record = result?;
+        let (Some(holding), Some(issuer)) = (record.get("Holding"), record.get("Issuer")) else {
+            return Err(format!("Issuer {:?} is missing Holding or Issuer", record).into());
+        };
+        issuers.insert(holding.clone(), issuer.clone());
+    }
+    event!(Level::INFO, "Parsed {} issuers", issuers.len());
+    Ok(issuers)
+}
+
+/// Aggregate holding results by their issuer, keeping holdings without a known issuer as they are
+pub fn group_holdings(
+    results: Vec<(String, f32)>,
+    issuers: &HashMap<String, String>,
+    unknown_label: &str,
+) -> Vec<(String, f32)> {
+    let mut grouped = HashMap::<String, f32>::new();
+    let mut unknown = None;
+    for (holding, weight) in results {
+        if holding == unknown_label {
+            unknown = Some(weight);
+            continue;
+        }
+        let issuer = issuers.get(&holding).cloned().unwrap_or(holding);
+        *grouped.entry(issuer).or_insert(0.) += weight;
+    }
+    let mut grouped = grouped.into_iter().collect::<Vec<_>>();
+    grouped.sort_by(|a, b| b.1.total_cmp(&a.1));
+    if let Some(unknown) = unknown {
+        grouped.push((unknown_label.to_string(), unknown));
+    }
+    grouped
+}
+
+/// Check the rules against the analysis results and print a `PASS`/`FAIL` line per rule.
+/// Returns the number of failed rules.
+pub fn check_rules(
+    rules: &[Rule],
+    data: &[(Exposure, Vec<(String, f32)>)],
+    ter: f32,
+    unknown: &str,
+) -> usize {
+    let mut failed = 0;
+    for rule in rules {
+        let values = match rule.exposure {
+            None => vec![(String::new(), ter)],
+            Some(exposure) => {
+                let results = data
+                    .iter()
+                    .find(|(e, _)| *e == exposure)
+                    .map(|(_, results)| results.as_slice())
+                    .unwrap_or_default();
+                if rule.label == "*" {
+                    results
+                        .iter()
+                        .filter(|(label, _)| label != unknown)
+                        .cloned()
+                        .collect()
+                } else {
+                    let value = results
+                        .iter()
+                        .find(|(label, _)| label.eq_ignore_ascii_case(&rule.label))
+                        .map_or(0., |(_, v)| *v);
+                    vec![(rule.label.clone(), value)]
+                }
+            }
+        };
+        let exposure = rule
+            .exposure
+            .map_or("TER".to_string(), |exposure| exposure.to_string());
+        let mut rule_failed = false;
+        for (label, value) in values {
+            let pass = rule.comparator.holds(value, rule.threshold);
+            rule_failed |= !pass;
+            let name = if label.is_empty() {
+                exposure.clone()
+            } else {
+                format!("{} {}", exposure, label)
+            };
+            println!(
+                "{} {} {} {}% (actual {})",
+                if pass { "PASS" } else { "FAIL" },
+                name,
+                rule.comparator,
+                rule.threshold,
+                format_percent(value)
+            );
+        }
+        if rule_failed {
+            failed += 1;
+        }
+    }
+    println!("{} of {} rules failed", failed, rules.len());
+    failed
+}
+
+/// Compare the portfolio weights with those of a reference portfolio, eg a broker statement, and
+/// print an `OK`/`DIFF` line per ISIN. Returns the number of ISINs whose weights differ by more
+/// than `tolerance` percentage points.
+pub fn reconcile(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    reference: &HashMap<String, f32>,
+    tolerance: f32,
+) -> usize {
+    let isins = portfolio
+        .keys()
+        .chain(reference.keys())
+        .collect::<BTreeSet<_>>();
+    println!(
+        "{:<4} {:<14} {:<40} {:>10} {:>10} {:>8}",
+        "", "ISIN", "Name", "Portfolio", "Reference", "Diff"
+    );
+    let mut mismatches = 0;
+    for isin in isins {
+        let name = securities
+            .get(isin)
+            .map(|security| security.name.as_str())
+            .unwrap_or_default();
+        let weight = portfolio.get(isin).copied().unwrap_or_default() * 100.;
+        let reference_weight = reference.get(isin).copied().unwrap_or_default() * 100.;
+        let diff = weight - reference_weight;
+        let matches = diff.abs() <= tolerance;
+        if !matches {
+            mismatches += 1;
+        }
+        println!(
+            "{:<4} {:<14} {:<40.40} {:>10} {:>10} {:>+7.2}%",
+            if matches { "OK" } else { "DIFF" },
+            isin,
+            name,
+            format_percent(weight),
+            format_percent(reference_weight),
+            if diff.abs() < 0.005 { 0. } else { diff }
+        );
+    }
+    println!(
+        "{} ISINs differ from the reference by more than {}%",
+        mismatches, tolerance
+    );
+    mismatches
+}
+
+/// Find a `YYYY-MM-DD` date in a text, eg a portfolio file name like `portfolio_2023-01-31`
+pub fn find_date(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    (0..bytes.len().saturating_sub(9))
+        .filter_map(|start| text.get(start..start + 10))
+        .find(|candidate| is_date(candidate))
+        .map(str::to_string)
+}
+
+/// Check that a text is a date in the `YYYY-MM-DD` format
+pub fn is_date(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(idx, b)| match idx {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+        && matches!(text[5..7].parse::<u8>(), Ok(1..=12))
+        && matches!(text[8..10].parse::<u8>(), Ok(1..=31))
+}
+
+/// Format a time as a `YYYY-MM-DD` date in UTC
+pub fn format_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() / 86400) as i64;
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Append the analysis results of a run as one JSON line to a history file
+pub fn append_history(
+    file_path: &str,
+    data: &[(Exposure, Vec<(String, f32)>)],
+    total: Option<f32>,
+    metrics: &Metrics,
+    conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    // Raw values keep the full precision of the results as fractions for further calculations
+    let value = |value: f32| {
+        if conf.raw_values {
+            value as f64 / 100.
+        } else {
+            round_json(value)
+        }
+    };
+    let exposures = data
+        .iter()
+        .map(|(exposure, results)| {
+            let results = results
+                .iter()
+                .map(|(label, v)| (label.clone(), serde_json::json!(value(*v))))
+                .collect::<serde_json::Map<_, _>>();
+            (exposure.to_string(), serde_json::Value::Object(results))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let mut entry = serde_json::json!({
+        "date": conf.as_of,
+        "portfolio": conf.output_file_name.to_string_lossy(),
+        "unit": if conf.raw_values { "fraction" } else { "percent" },
+        "weight_by": match conf.weight_by {
+            WeightBy::Capital => "capital",
+            WeightBy::Risk => "risk",
+        },
+        "exposure_basis": match conf.exposure_basis {
+            ExposureBasis::Reported => "reported",
+            ExposureBasis::Classified => "classified",
+        },
+        "currency": total.map(|_| &conf.currency_code),
+        "total": total.map(|total| {
+            if conf.raw_values {
+                total as f64
+            } else {
+                (total as f64 * 100.).round() / 100.
+            }
+        }),
+        "ter": value(metrics.ter),
+        "exposures": exposures,
+    });
+    // Optional metrics are only recorded when the securities have the data
+    for (key, metric) in [
+        ("div_growth", metrics.div_growth),
+        ("payout_ratio", metrics.payout_ratio),
+    ] {
+        if let Some(metric) = metric {
+            entry[key] = serde_json::json!(value(metric));
+        }
+    }
+    // Durations and ratios that aren't percentages stay as they are with raw values
+    for (key, metric) in [("duration", metrics.duration), ("pe", metrics.pe)] {
+        if let Some(metric) = metric {
+            entry[key] = serde_json::json!(if conf.raw_values {
+                metric as f64
+            } else {
+                round_json(metric)
+            });
+        }
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path)?;
+    writeln!(file, "{}", entry)?;
+    event!(
+        Level::INFO,
+        "Appended {} results to {}",
+        conf.as_of,
+        file_path
+    );
+    Ok(())
+}
+
+/// Print the weights of `labels` of an exposure in every run of a history file. Without labels,
+/// the `limit` largest labels of the latest run are shown.
+pub fn print_history(
+    file_path: &str,
+    exposure: Exposure,
+    labels: &[String],
+    limit: usize,
+    unknown: &str,
+) -> Result<(), Box<dyn Error>> {
+    let entries = fs::read_to_string(file_path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(idx, line)| {
+            serde_json::from_str::<serde_json::Value>(line).map_err(|err| {
+                format!(
+                    "Bad history entry on line {} of {}: {}",
+                    idx + 1,
+                    file_path,
+                    err
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // Entries written with --raw-values hold fractions instead of percentages
+    let percent = |entry: &serde_json::Value, value: &serde_json::Value| {
+        let scale = if entry["unit"] == "fraction" {
+            100.
+        } else {
+            1.
+        };
+        value.as_f64().map(|value| value * scale)
+    };
+    let weights = |entry: &serde_json::Value| {
+        entry["exposures"][exposure.to_string()]
+            .as_object()
+            .map(|results| {
+                results
+                    .iter()
+                    .filter_map(|(label, value)| Some((label.clone(), percent(entry, value)?)))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default()
+    };
+    let labels = if labels.is_empty() {
+        let mut latest = entries
+            .last()
+            .map(weights)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(label, _)| label != unknown)
+            .collect::<Vec<_>>();
+        latest.sort_by(|a, b| b.1.total_cmp(&a.1));
+        latest
+            .into_iter()
+            .take(limit)
+            .map(|(label, _)| label)
+            .collect()
+    } else {
+        labels.to_vec()
+    };
+    print!("{:<10} {:>7}", "Date", "TER");
+    for label in &labels {
+        print!(" {:>12.12}", label);
+    }
+    println!();
+    for entry in &entries {
+        let weights = weights(entry);
+        print!(
+            "{:<10} {:>7}",
+            entry["date"].as_str().unwrap_or("-"),
+            percent(entry, &entry["ter"]).map_or("-".to_string(), |ter| format!("{:.3}%", ter))
+        );
+        for label in &labels {
+            match weights.get(label) {
+                Some(weight) => print!(" {:>12}", format_percent(*weight as f32)),
+                None => print!(" {:>12}", "-"),
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Print a built-in mapping from the config as CSV
+pub fn print_mapping(mapping: Mapping) {
+    let sorted = |map: &HashMap<&str, &str>| {
+        let mut rows = map
+            .iter()
+            .map(|(key, value)| format!("{},{}", key, value))
+            .collect::<Vec<_>>();
+        rows.sort();
+        rows
+    };
+    let splits = |map: &HashMap<&str, Vec<(&str, f32)>>| {
+        let mut rows = map
+            .iter()
+            .flat_map(|(key, split)| {
+                split
+                    .iter()
+                    .map(move |(value, share)| format!("{},{},{}", key, value, share * 100.))
+            })
+            .collect::<Vec<_>>();
+        rows.sort();
+        rows
+    };
+    let set = |set: &HashSet<&str>| {
+        let mut rows = set
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>();
+        rows.sort();
+        rows
+    };
+    let (header, rows) = match mapping {
+        Mapping::Sectors => ("Sector", set(&SECTORS)),
+        Mapping::SectorSynonyms => ("Synonym,Sector", sorted(&SECTOR_SYNONYMS)),
+        Mapping::Regions => ("Country,Region", sorted(&COUNTRY_TO_REGION)),
+        Mapping::Markets => ("Country,Market", sorted(&COUNTRY_TO_MARKET)),
+        Mapping::Continents => ("Country,Continent", sorted(&COUNTRY_TO_CONTINENT)),
+        Mapping::CurrencyBlocs => ("Country,Bloc", sorted(&COUNTRY_TO_BLOC)),
+        Mapping::RegionMarkets => ("Region,Market,Share", splits(&REGION_TO_MARKET)),
+        Mapping::MarketRegions => ("Market,Region,Share", splits(&MARKET_TO_REGION)),
+        Mapping::RegionContinents => ("Region,Continent,Share", splits(&REGION_TO_CONTINENT)),
+        Mapping::FundKeywords => ("Keyword", set(&FUND_KEYWORDS)),
+        Mapping::FixedIncomeClasses => ("AssetClass", set(&FIXED_INCOME_CLASSES)),
+        Mapping::Currencies => ("Code,Symbol", sorted(&CURRENCY_SYMBOLS)),
+    };
+    println!("{}", header);
+    for row in rows {
+        println!("{}", row);
+    }
+}
+
+/// Round a percentage for JSON output, where an `f32` would otherwise be written with the noise
+/// of its `f64` conversion
+fn round_json(value: f32) -> f64 {
+    (value as f64 * 10000.).round() / 10000.
+}
+
+/// Herfindahl-Hirschman index of the classified entries of an exposure, on a scale from 0 to
+/// 10000 where 10000 means a single entry
+fn hhi(results: &[(String, f32)], unknown: &str) -> f32 {
+    results
+        .iter()
+        .filter(|(k, _)| k != unknown)
+        .map(|(_, v)| v * v)
+        .sum()
+}
+
+/// Rebase an exposure on its classified part, dropping the unknown share and scaling the other
+/// labels to add up to 100%. Returns the classified share before rebasing, leaving exposures
+/// without any classified labels as they are
+pub fn rebase_on_classified(results: &mut Vec<(String, f32)>, unknown: &str) -> f32 {
+    let classified = results
+        .iter()
+        .filter(|(k, _)| k != unknown)
+        .map(|(_, v)| v)
+        .sum::<f32>();
+    if classified > 0. {
+        results.retain(|(k, _)| k != unknown);
+        for (_, v) in results.iter_mut() {
+            *v *= 100. / classified;
+        }
+    }
+    classified
+}
+
+/// Effective number of classified entries of an exposure, the inverse of the HHI of their
+/// normalized weights, or `None` when nothing is classified
+pub fn effective_count(results: &[(String, f32)], unknown: &str) -> Option<f32> {
+    let classified = results
+        .iter()
+        .filter(|(k, _)| k != unknown)
+        .map(|(_, v)| v)
+        .sum::<f32>();
+    let hhi = hhi(results, unknown);
+    (hhi > 0.).then(|| classified * classified / hhi)
+}
+
+/// HTML side panel listing the top entry, HHI and unknown share of every exposure
+fn stats_panel(data: &[(Exposure, Vec<(String, f32)>)], conf: &Conf) -> String {
+    let unknown_label = &conf.unknown_label;
+    let rows = data
+        .iter()
+        .zip(completeness(data, conf))
+        .map(|((exposure, results), (_, complete))| {
+            let top = results
+                .iter()
+                .find(|(k, _)| k != unknown_label)
+                .map_or("-".to_string(), |(k, v)| {
+                    format!("{} ({})", html_escape(k), format_percent(*v))
+                });
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{:.0}</td><td>{}</td></tr>",
+                exposure,
+                top,
+                hhi(results, unknown_label),
+                format_percent(100. - complete)
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(
+        r#"<div style="position: fixed; top: 10px; right: 10px; padding: 8px; background: rgba(255, 255, 255, 0.9); border: 1px solid #ccc; font: 12px sans-serif; z-index: 10;">
+<table>
+<tr><th align="left">Exposure</th><th align="left">Top</th><th align="left">HHI</th><th align="left">Unknown</th></tr>
+{}
+</table>
+</div>"#,
+        rows.join("\n")
+    )
+}
+
+/// Securities contributing to every plotted label, as JSON keyed by exposure and the label shown
+/// on the bar
+pub fn drill_down(
+    securities: &HashMap<String, Security>,
+    portfolio: &HashMap<String, f32>,
+    data: &[(Exposure, Vec<(String, f32)>)],
+    conf: &Conf,
+) -> Result<serde_json::Map<String, serde_json::Value>, Box<dyn Error>> {
+    let mut drill_down = serde_json::Map::new();
+    for (exposure, results) in data {
+        let mut labels = serde_json::Map::new();
+        for (label, _) in results.iter().take(conf.limit) {
+            let rows = contributions(securities, portfolio, *exposure, label, conf)?
+                .into_iter()
+                .map(|(isin, weight)| {
+                    let name = securities.get(&isin).map_or("", |security| &security.name);
+                    serde_json::json!([isin, name, (weight as f64 * 100.).round() / 100.])
+                })
+                .collect();
+            let shown = match (&conf.holding_tickers, exposure) {
+                (Some(tickers), Exposure::Holding) => tickers.get(label).unwrap_or(label),
+                _ => label,
+            };
+            labels.insert(shown.clone(), serde_json::Value::Array(rows));
+        }
+        drill_down.insert(exposure.to_string(), serde_json::Value::Object(labels));
+    }
+    Ok(drill_down)
+}
+
+/// Embed the drill-down data of the plotted exposures in the order of their axes with a script
+/// listing the contributing securities of a clicked bar
+fn drill_down_panel(
+    data: &[(Exposure, Vec<(String, f32)>)],
+    drill_down: &serde_json::Map<String, serde_json::Value>,
+) -> String {
+    let axes = data
+        .iter()
+        .map(|(exposure, _)| {
+            let exposure = exposure.to_string();
+            let labels = drill_down.get(&exposure).cloned().unwrap_or_default();
+            serde_json::json!({ "exposure": exposure, "labels": labels })
+        })
+        .collect::<Vec<_>>();
+    format!(
+        r#"<div id="drill-down" style="display: none; position: fixed; bottom: 10px; left: 10px; max-height: 40%; overflow-y: auto; padding: 8px; background: rgba(255, 255, 255, 0.9); border: 1px solid #ccc; font: 12px sans-serif; z-index: 10;"></div>
+<script type="application/json" id="drill-down-data">{}</script>
+<script type="module">
+const axes = JSON.parse(document.getElementById("drill-down-data").textContent);
+const panel = document.getElementById("drill-down");
+const escape = (text) => String(text).replace(/[&<>"]/g, (c) => ({{ "&": "&amp;", "<": "&lt;", ">": "&gt;", '"': "&quot;" }})[c]);
+document.getElementById("plotly-html-element").on("plotly_click", (event) => {{
+    const point = event.points[0];
+    const axis = axes[(parseInt((point.data.xaxis || "x").slice(1)) || 1) - 1];
+    const rows = axis && axis.labels[point.x];
+    if (!rows) return;
+    panel.innerHTML = `<b>${{escape(axis.exposure)}}: ${{escape(point.x)}}</b><table>`
+        + rows.map(([isin, name, weight]) => `<tr><td>${{escape(isin)}}</td><td>${{escape(name)}}</td><td align="right">${{weight.toFixed(2)}}%</td></tr>`).join("")
+        + "</table>";
+    panel.style.display = "block";
+}});
+</script>"#,
+        serde_json::Value::Array(axes)
+            .to_string()
+            .replace("</", "<\\/")
+    )
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Parse a palette given as the name of a predefined palette or as a comma separated list of
+/// `#RRGGBB` colors
+pub fn parse_palette(palette: &str) -> Result<Vec<String>, String> {
+    if let Some(colors) = PALETTES.get(palette.to_ascii_lowercase().as_str()) {
+        return Ok(colors.iter().map(|color| color.to_string()).collect());
+    }
+    let colors = palette
+        .split(',')
+        .map(|color| color.trim().to_string())
+        .collect::<Vec<_>>();
+    if let Some(color) = colors.iter().find(|color| {
+        color.len() != 7
+            || !color.starts_with('#')
+            || !color[1..].chars().all(|c| c.is_ascii_hexdigit())
+    }) {
+        let mut names = PALETTES.keys().collect::<Vec<_>>();
+        names.sort();
+        return Err(format!(
+            "Bad palette color {:?}, expected #RRGGBB colors or one of {:?}",
+            color, names
+        ));
+    }
+    Ok(colors)
+}
+
+/// Pick a color for a label from the palette. The color only depends on the label, so the same
+/// label keeps its color between graphs and runs. The unknown and other labels are gray, lighter
+/// for the other label, so they stand apart from the labels of the palette.
+fn label_color(label: &str, palette: &[String], conf: &Conf) -> String {
+    if label == conf.unknown_label {
+        return "#808080".to_string();
+    }
+    if label == conf.other_label {
+        return "#BFBFBF".to_string();
+    }
+    palette[(fnv1a(label.as_bytes()) % palette.len() as u64) as usize].clone()
+}
+
+/// FNV-1a hash, used where hashes must be stable between runs and releases, which the standard
+/// library hasher does not guarantee
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Fingerprint of the analysis results rounded to two decimals, independent of the order of the
+/// results and the formatting of the input files
+pub fn fingerprint(data: &[(Exposure, Vec<(String, f32)>)]) -> String {
+    let mut lines = data
+        .iter()
+        .flat_map(|(exposure, results)| {
+            results.iter().map(move |(label, value)| {
+                format!("{}\t{}\t{}", exposure, label, format_percent(*value))
+            })
+        })
+        .collect::<Vec<_>>();
+    lines.sort();
+    format!("{:016x}", fnv1a(lines.join("\n").as_bytes()))
+}
+
+/// Share of the portfolio in percent that is classified, ie not `Unknown`, per exposure. Rebased
+/// exposures report their classified share from before the rebasing
+fn completeness(data: &[(Exposure, Vec<(String, f32)>)], conf: &Conf) -> Vec<(Exposure, f32)> {
+    data.iter()
+        .map(|(exposure, results)| {
+            let reported = conf.reported_completeness.as_ref().and_then(|reported| {
+                reported
+                    .iter()
+                    .find(|(reported, _)| reported == exposure)
+                    .map(|(_, value)| *value)
+            });
+            let unknown = results
+                .iter()
+                .find(|(label, _)| *label == conf.unknown_label)
+                .map_or(0., |(_, value)| *value);
+            (*exposure, reported.unwrap_or(100. - unknown))
+        })
+        .collect()
+}
+
+/// Exposures whose classified share is below `conf.min_completeness`
+fn sparse_exposures(data: &[(Exposure, Vec<(String, f32)>)], conf: &Conf) -> Vec<(Exposure, f32)> {
+    let Some(min) = conf.min_completeness else {
+        return Vec::new();
+    };
+    completeness(data, conf)
+        .into_iter()
+        .filter(|(_, value)| *value < min)
+        .collect()
+}
+
+/// Fail when an exposure is below `conf.min_completeness`, or only warn about it when the charts
+/// carry a banner instead
+pub fn check_completeness(
+    data: &[(Exposure, Vec<(String, f32)>)],
+    conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    let sparse = sparse_exposures(data, conf);
+    if sparse.is_empty() {
+        return Ok(());
+    }
+    let message = format!(
+        "Classified share below the minimum completeness of {}%: {}",
+        conf.min_completeness.unwrap_or_default(),
+        describe_completeness(&sparse)
+    );
+    if !conf.completeness_banner {
+        return Err(message.into());
+    }
+    event!(Level::WARN, "{}", message);
+    Ok(())
+}
+
+fn describe_completeness(sparse: &[(Exposure, f32)]) -> String {
+    sparse
+        .iter()
+        .map(|(exposure, value)| format!("{} {:.1}%", exposure, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Format a percentage with two decimals, printing values that round to zero as `0.00%` instead
+/// of `-0.00%`.
+fn format_percent(value: f32) -> String {
+    let value = if value.abs() < 0.005 { 0. } else { value };
+    format!("{:.2}%", value)
+}
+
+/// Round percentages to two decimals so that they add up to their rounded total, giving the
+/// hundredths lost by rounding down to the values with the largest remainders
+fn largest_remainder(values: &[f32]) -> Vec<f32> {
+    let scaled = values.iter().map(|v| *v as f64 * 100.).collect::<Vec<_>>();
+    let mut rounded = scaled.iter().map(|v| v.floor()).collect::<Vec<_>>();
+    let missing = scaled.iter().sum::<f64>().round() - rounded.iter().sum::<f64>();
+    let mut order = (0..scaled.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| (scaled[b] - rounded[b]).total_cmp(&(scaled[a] - rounded[a])));
+    for &position in order.iter().take(missing.max(0.) as usize) {
+        rounded[position] += 1.;
+    }
+    rounded.into_iter().map(|v| (v / 100.) as f32).collect()
+}
+
+/// Text of a stacked bar segment, left empty for segments too small to show
+fn segment_text(value: f32) -> String {
+    if value < 0.005 {
+        String::new()
+    } else {
+        format_percent(value)
+    }
+}
+
+/// Color a weight on a green-yellow-red gradient by how far it is from `baseline`, saturating at
+/// a quarter and four times the baseline.
+fn heat_color(weight: f32, baseline: f32) -> Rgb {
+    let ratio = (weight / baseline).max(f32::MIN_POSITIVE);
+    let t = (ratio.log(4.) / 2. + 0.5).clamp(0., 1.);
+    let lerp = |from: u8, to: u8, t: f32| (from as f32 + (to as f32 - from as f32) * t) as u8;
+    if t < 0.5 {
+        let t = t * 2.;
+        Rgb::new(lerp(26, 230, t), lerp(152, 200, t), lerp(80, 40, t))
+    } else {
+        let t = (t - 0.5) * 2.;
+        Rgb::new(lerp(230, 215, t), lerp(200, 48, t), lerp(40, 39, t))
+    }
+}
+
+/// Write an output file through a temporary file next to it that is renamed over it, so a failed
+/// write never leaves a truncated output. The rename is retried a few times, as a browser showing
+/// the file can briefly lock it on Windows.
+fn write_output(path: &str, contents: impl AsRef<[u8]>) -> Result<(), Box<dyn Error>> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, contents).map_err(|err| format!("Failed to write {}: {}", tmp, err))?;
+    let mut attempt = 1;
+    loop {
+        match fs::rename(&tmp, path) {
+            Ok(()) => return Ok(()),
+            Err(_) if attempt < 3 => {
+                attempt += 1;
+                thread::sleep(Duration::from_millis(200));
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(format!(
+                    "Failed to write {}: {}. Close any program that has the file open and check the permissions of the folder",
+                    path, err
+                )
+                .into());
+            }
+        }
+    }
+}
+
+/// `fixed_income` is the weight of each holding held through fixed income securities, which the
+/// Holding graph stacks on top of its equity weight
+pub fn plot_grid(
+    data: Vec<(Exposure, Vec<(String, f32)>)>,
+    total: Option<f32>,
+    metrics: &Metrics,
+    drill_down: Option<&serde_json::Map<String, serde_json::Value>>,
+    fixed_income: Option<&HashMap<String, f32>>,
+    conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    let output_file = if !conf.output_folder.is_empty() {
+        format!(
+            "{}/{}",
+            conf.output_folder,
+            conf.output_file_name.to_string_lossy()
+        )
+    } else {
+        conf.output_file_name.to_string_lossy().to_string()
+    };
+    if !conf.separate_files {
+        return write_plot(
+            data,
+            &output_file,
+            total,
+            metrics,
+            drill_down,
+            fixed_income,
+            conf,
+        );
+    }
+    for (exposure, results) in data {
+        write_plot(
+            vec![(exposure, results)],
+            &format!("{}-{}", output_file, exposure),
+            total,
+            metrics,
+            drill_down,
+            fixed_income,
+            conf,
+        )?;
+    }
+    Ok(())
+}
+
+/// Title of the analysis with the portfolio, its date and the summary metrics
+fn report_title(metrics: &Metrics, conf: &Conf) -> String {
+    let subject = match conf.weight_by {
+        WeightBy::Capital => "Asset",
+        WeightBy::Risk => "Risk",
+    };
+    let part = match conf.exposure_basis {
+        ExposureBasis::Reported => "for",
+        ExposureBasis::Classified => "of the classified part of",
+    };
+    let mut title = format!(
+        "{} exposure {} {} portfolio as of {}, TER {:.3}%",
+        subject,
+        part,
+        conf.output_file_name.to_string_lossy(),
+        conf.as_of,
+        metrics.ter
+    );
+    if let Some(net_ter) = metrics.net_ter {
+        title.push_str(&format!(" (net {:.3}%)", net_ter));
+    }
+    if let Some(total_cost) = metrics.total_cost {
+        title.push_str(&format!(", total cost {:.3}%", total_cost));
+    }
+    if let Some(duration) = metrics.duration {
+        title.push_str(&format!(", duration {:.2} years", duration));
+    }
+    if let Some(pe) = metrics.pe {
+        title.push_str(&format!(", P/E {:.1}", pe));
+    }
+    if let Some(div_growth) = metrics.div_growth {
+        title.push_str(&format!(", dividend growth {:.2}%", div_growth));
+    }
+    if let Some(payout_ratio) = metrics.payout_ratio {
+        title.push_str(&format!(", payout ratio {:.1}%", payout_ratio));
+    }
+    title
+}
+
+/// Name of an exposure as shown in the outputs
+fn exposure_name(exposure: Exposure, conf: &Conf) -> String {
+    match exposure {
+        Exposure::Region if conf.continents => "Continent".to_string(),
+        Exposure::CurrencyBloc => "Currency Bloc".to_string(),
+        _ => exposure.to_string(),
+    }
+}
+
+/// Write the exposures as Markdown tables under a heading with the summary metrics to
+/// `file_path`, or to stdout when it is `-`. Every exposure shows its first `conf.limit` labels like
+/// the plot, with their amounts when the portfolio has a total value.
+pub fn write_markdown(
+    file_path: &str,
+    data: &[(Exposure, Vec<(String, f32)>)],
+    total: Option<f32>,
+    metrics: &Metrics,
+    conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    let escape = |text: &str| text.replace('|', "\\|");
+    let mut markdown = format!("# {}\n\n", report_title(metrics, conf));
+    if let Some(total) = total {
+        markdown.push_str(&format!("Total value {:.2} {}\n\n", total, conf.currency));
+    }
+    markdown.push_str(&format!(
+        "Data completeness: {}\n",
+        completeness(data, conf)
+            .iter()
+            .map(|(exposure, value)| format!("{} {:.0}%", exposure_name(*exposure, conf), value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ));
+    for (exposure, results) in data {
+        let name = escape(&exposure_name(*exposure, conf));
+        markdown.push_str(&format!("\n## {}\n\n", name));
+        match total {
+            Some(_) => {
+                markdown.push_str(&format!("| {} | Weight | Amount |\n|:--|--:|--:|\n", name))
+            }
+            None => markdown.push_str(&format!("| {} | Weight |\n|:--|--:|\n", name)),
+        }
+        for (label, value) in results.iter().take(conf.limit) {
+            markdown.push_str(&format!(
+                "| {} | {} |",
+                escape(label),
+                format_percent(*value)
+            ));
+            if let Some(total) = total {
+                markdown.push_str(&format!(" {:.2} {} |", value * total / 100., conf.currency));
+            }
+            markdown.push('\n');
+        }
+        if results.len() > conf.limit {
+            markdown.push_str(&format!(
+                "\n{} more labels not shown\n",
+                results.len() - conf.limit
+            ));
+        }
+    }
+    if file_path == "-" {
+        print!("{}", markdown);
+        Ok(())
+    } else {
+        write_output(file_path, &markdown)
+    }
+}
+
+/// Cell of a worksheet
+#[cfg(feature = "xlsx")]
+enum Cell {
+    Text(String),
+    Number(f64),
+}
+
+/// Rows of the summary sheet of the workbook, the metrics of the analysis that are known
+#[cfg(feature = "xlsx")]
+fn summary_rows(metrics: &Metrics, total: Option<f32>, conf: &Conf) -> Vec<(&'static str, Cell)> {
+    let mut rows = vec![
+        (
+            "Portfolio",
+            Cell::Text(conf.output_file_name.to_string_lossy().to_string()),
+        ),
+        ("Date", Cell::Text(conf.as_of.clone())),
+        (
+            "Weighted by",
+            Cell::Text(
+                match conf.weight_by {
+                    WeightBy::Capital => "capital",
+                    WeightBy::Risk => "risk",
+                }
+                .to_string(),
+            ),
+        ),
+        (
+            "Exposure basis",
+            Cell::Text(
+                match conf.exposure_basis {
+                    ExposureBasis::Reported => "reported",
+                    ExposureBasis::Classified => "classified",
+                }
+                .to_string(),
+            ),
+        ),
+    ];
+    if let Some(total) = total {
+        rows.push(("Total value", Cell::Number(total as f64)));
+        rows.push(("Currency", Cell::Text(conf.currency_code.clone())));
+    }
+    for (name, metric) in [
+        ("TER %", Some(metrics.ter)),
+        ("Net TER %", metrics.net_ter),
+        ("Total cost %", metrics.total_cost),
+        ("Duration (years)", metrics.duration),
+        ("P/E", metrics.pe),
+        ("Dividend growth %", metrics.div_growth),
+        ("Payout ratio %", metrics.payout_ratio),
+    ] {
+        if let Some(metric) = metric {
+            rows.push((name, Cell::Number(metric as f64)));
+        }
+    }
+    rows
+}
+
+/// XML of a worksheet with the given rows
+#[cfg(feature = "xlsx")]
+fn sheet_xml(rows: &[Vec<Cell>]) -> String {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData>"#,
+    );
+    for (row_idx, row) in rows.iter().enumerate() {
+        xml.push_str(&format!(r#"<row r="{}">"#, row_idx + 1));
+        for (col_idx, cell) in row.iter().enumerate() {
+            let reference = format!("{}{}", (b'A' + col_idx as u8) as char, row_idx + 1);
+            match cell {
+                Cell::Number(number) if number.is_finite() => {
+                    xml.push_str(&format!(r#"<c r="{}"><v>{}</v></c>"#, reference, number))
+                }
+                Cell::Number(_) => {}
+                Cell::Text(text) => xml.push_str(&format!(
+                    r#"<c r="{}" t="inlineStr"><is><t>{}</t></is></c>"#,
+                    reference,
+                    escape(text)
+                )),
+            }
+        }
+        xml.push_str("</row>");
+    }
+    xml.push_str("</sheetData></worksheet>");
+    xml
+}
+
+/// Write the analysis as an XLSX workbook with a summary sheet of the metrics and a sheet per
+/// exposure with all of its labels, their weights in percent and their amounts when the portfolio
+/// has a total value
+#[cfg(feature = "xlsx")]
+pub fn write_xlsx(
+    file_path: &str,
+    data: &[(Exposure, Vec<(String, f32)>)],
+    total: Option<f32>,
+    metrics: &Metrics,
+    conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    use std::io::Write;
+
+    let mut sheets = vec![(
+        "Summary".to_string(),
+        summary_rows(metrics, total, conf)
+            .into_iter()
+            .map(|(name, value)| vec![Cell::Text(name.to_string()), value])
+            .collect::<Vec<_>>(),
+    )];
+    for (exposure, results) in data {
+        let name = exposure_name(*exposure, conf);
+        let mut header = vec![Cell::Text(name.clone()), Cell::Text("Weight %".to_string())];
+        if total.is_some() {
+            header.push(Cell::Text(format!("Amount {}", conf.currency_code)));
+        }
+        let mut rows = vec![header];
+        for (label, value) in results {
+            let mut row = vec![Cell::Text(label.clone()), Cell::Number(round_json(*value))];
+            if let Some(total) = total {
+                row.push(Cell::Number((*value as f64 * total as f64).round() / 100.));
+            }
+            rows.push(row);
+        }
+        sheets.push((name, rows));
+    }
+
+    let mut content_types = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>"#,
+    );
+    let mut workbook = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><sheets>"#,
+    );
+    let mut workbook_rels = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+    );
+    for (idx, (name, _)) in sheets.iter().enumerate() {
+        let id = idx + 1;
+        content_types.push_str(&format!(
+            r#"<Override PartName="/xl/worksheets/sheet{}.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>"#,
+            id
+        ));
+        workbook.push_str(&format!(
+            r#"<sheet name="{}" sheetId="{}" r:id="rId{}"/>"#,
+            name, id, id
+        ));
+        workbook_rels.push_str(&format!(
+            r#"<Relationship Id="rId{}" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet{}.xml"/>"#,
+            id, id
+        ));
+    }
+    content_types.push_str("</Types>");
+    workbook.push_str("</sheets></workbook>");
+    workbook_rels.push_str("</Relationships>");
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default();
+    let mut parts = vec![
+        ("[Content_Types].xml".to_string(), content_types),
+        (
+            "_rels/.rels".to_string(),
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#.to_string(),
+        ),
+        ("xl/workbook.xml".to_string(), workbook),
+        ("xl/_rels/workbook.xml.rels".to_string(), workbook_rels),
+    ];
+    for (idx, (_, rows)) in sheets.iter().enumerate() {
+        parts.push((
+            format!("xl/worksheets/sheet{}.xml", idx + 1),
+            sheet_xml(rows),
+        ));
+    }
+    for (name, contents) in parts {
+        zip.start_file(name, options)?;
+        zip.write_all(contents.as_bytes())?;
+    }
+    write_output(file_path, zip.finish()?.into_inner())
+}
+
+#[cfg(not(feature = "xlsx"))]
+pub fn write_xlsx(
+    file_path: &str,
+    _data: &[(Exposure, Vec<(String, f32)>)],
+    _total: Option<f32>,
+    _metrics: &Metrics,
+    _conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    Err(format!(
+        "Writing the workbook {} requires building with the xlsx feature",
+        file_path
+    )
+    .into())
+}
+
+/// Plot the exposures in a grid with one row per exposure and write the outputs to `output_file`
+/// with the extension of each output format
+fn write_plot(
+    data: Vec<(Exposure, Vec<(String, f32)>)>,
+    output_file: &str,
+    total: Option<f32>,
+    metrics: &Metrics,
+    drill_down: Option<&serde_json::Map<String, serde_json::Value>>,
+    fixed_income: Option<&HashMap<String, f32>>,
+    conf: &Conf,
+) -> Result<(), Box<dyn Error>> {
+    let mut plot = Plot::new();
+    let panels = conf
+        .stats_panel
+        .then(|| stats_panel(&data, conf))
+        .into_iter()
+        .chain(drill_down.map(|drill_down| drill_down_panel(&data, drill_down)))
+        .collect::<Vec<_>>();
+
+    let y_title = match conf.weight_by {
+        WeightBy::Capital => Y_AXIS_TITLE,
+        WeightBy::Risk => RISK_Y_AXIS_TITLE,
+    };
+    let mut title = report_title(metrics, conf);
+    let completeness = completeness(&data, conf);
+    if conf.completeness_subtitle {
+        title.push_str(&format!(
+            "<br><sub>Data completeness {:.0}% ({})</sub>",
+            completeness.iter().map(|(_, value)| value).sum::<f32>() / completeness.len() as f32,
+            completeness
+                .iter()
+                .map(|(exposure, value)| format!("{} {:.0}%", exposure, value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    let mut layout = Layout::new()
+        .title(Title::new(title.as_str()))
+        .height(if data.len() > 1 { 1024 } else { 512 })
+        .grid(
+            LayoutGrid::new()
+                .rows(data.len())
+                .columns(1)
+                .pattern(GridPattern::Independent),
+        )
+        .show_legend(false);
+    if let Theme::Dark = conf.theme {
+        layout = layout.template(&*PLOTLY_DARK);
+    }
+    let sparse = sparse_exposures(&data, conf);
+    if !sparse.is_empty() {
+        layout.add_annotation(
+            Annotation::new()
+                .text(format!(
+                    "<b>Incomplete data</b>: {} classified, below the minimum of {}%",
+                    describe_completeness(&sparse),
+                    conf.min_completeness.unwrap_or_default()
+                ))
+                .x_ref("paper")
+                .y_ref("paper")
+                .x(0.5)
+                .y(0.5)
+                .x_anchor(Anchor::Center)
+                .y_anchor(Anchor::Middle)
+                .font(Font::new().size(20).color(NamedColor::White))
+                .background_color(Rgb::new(215, 48, 39))
+                .opacity(0.9)
+                .border_pad(12.)
+                .show_arrow(false),
+        );
+    }
+    for (idx, ((exposure, data), (_, complete))) in data.into_iter().zip(completeness).enumerate() {
+        let name = exposure_name(exposure, conf);
+        let mut x_title = if conf.unknown_in_title && complete < 100. {
+            format!("{} ({:.0}% unknown)", name, 100. - complete)
+        } else {
+            name.clone()
+        };
+        // The legend is off for the whole grid, so the axis names the stacked part instead
+        if exposure == Exposure::Holding && fixed_income.is_some() {
+            x_title.push_str(" (fixed income in orange)");
+        }
+        if let Some(count) = effective_count(&data, &conf.unknown_label) {
+            layout.add_annotation(
+                Annotation::new()
+                    .text(format!("{}: {:.1} effective", name, count))
+                    .x_ref(format!("x{} domain", idx + 1))
+                    .y_ref(format!("y{} domain", idx + 1))
+                    .x(1.)
+                    .y(1.)
+                    .x_anchor(Anchor::Right)
+                    .y_anchor(Anchor::Bottom)
+                    .show_arrow(false),
+            );
+        }
+        match idx {
+            0 => {
+                layout = layout
+                    .x_axis(Axis::new().title(Title::new(x_title.as_str())))
+                    .y_axis(Axis::new().title(Title::new(y_title)));
+            }
+            1 => {
+                layout = layout
+                    .x_axis2(Axis::new().title(Title::new(x_title.as_str())))
+                    .y_axis2(Axis::new().title(Title::new(y_title)));
+            }
+            2 => {
+                layout = layout
+                    .x_axis3(Axis::new().title(Title::new(x_title.as_str())))
+                    .y_axis3(Axis::new().title(Title::new(y_title)));
+            }
+            3 => {
+                layout = layout
+                    .x_axis4(Axis::new().title(Title::new(x_title.as_str())))
+                    .y_axis4(Axis::new().title(Title::new(y_title)));
+            }
+            4 => {
+                layout = layout
+                    .x_axis5(Axis::new().title(Title::new(x_title.as_str())))
+                    .y_axis5(Axis::new().title(Title::new(y_title)));
+            }
+            5 => {
+                layout = layout
+                    .x_axis6(Axis::new().title(Title::new(x_title.as_str())))
+                    .y_axis6(Axis::new().title(Title::new(y_title)));
+            }
+            6 => {
+                layout = layout
+                    .x_axis7(Axis::new().title(Title::new(x_title.as_str())))
+                    .y_axis7(Axis::new().title(Title::new(y_title)));
+            }
+            _ => {}
+        }
+        let known_count = data
+            .iter()
+            .filter(|(k, _)| *k != conf.unknown_label)
+            .count();
+        // Rounded over every label, so the shown ones add up to the total when none are cut off
+        let mut percents = data.iter().map(|(_, v)| *v).collect::<Vec<_>>();
+        if conf.round_labels {
+            percents = largest_remainder(&percents);
+        }
+        let mut data = data;
+        let mut omitted = data.split_off(conf.limit.min(data.len()));
+        let mut omitted_percents = percents.split_off(data.len());
+        // Sum the holdings cut off by the limit into a last bar, so the bars add up to the total.
+        // The unknown share keeps a bar of its own after it
+        let mut other_holdings = None;
+        if conf.other_holdings && exposure == Exposure::Holding {
+            let unknown = omitted
+                .iter()
+                .position(|(k, _)| *k == conf.unknown_label)
+                .map(|position| (omitted.remove(position), omitted_percents.remove(position)));
+            if !omitted.is_empty() {
+                let label = format!(
+                    "{} ({} holding{})",
+                    conf.other_label,
+                    omitted.len(),
+                    if omitted.len() == 1 { "" } else { "s" }
+                );
+                data.push((label.clone(), omitted.iter().map(|(_, v)| v).sum()));
+                percents.push(omitted_percents.iter().sum());
+                other_holdings = Some(label);
+            }
+            if let Some((unknown, percent)) = unknown {
+                data.push(unknown);
+                percents.push(percent);
+            }
+        }
+        let labels = data.iter().map(|(v, _)| v.to_owned()).collect::<Vec<_>>();
+        let values = data.iter().map(|(_, v)| *v).collect::<Vec<_>>();
+        for (position, label) in labels.iter().enumerate() {
+            for band in conf
+                .bands
+                .iter()
+                .filter(|band| band.exposure == exposure && band.label.eq_ignore_ascii_case(label))
+            {
+                layout.add_shape(
+                    Shape::new()
+                        .shape_type(ShapeType::Rect)
+                        .layer(ShapeLayer::Below)
+                        .x_ref(format!("x{}", idx + 1))
+                        .y_ref(format!("y{}", idx + 1))
+                        .x0(position as f64 - 0.45)
+                        .x1(position as f64 + 0.45)
+                        .y0(band.min as f64)
+                        .y1(band.max as f64)
+                        .fill_color("rgba(44, 160, 44, 0.25)")
+                        .line(ShapeLine::new().width(0.)),
+                );
+            }
+        }
+
+        let bar_text = |position: usize, v: f32| match (conf.label_value, total) {
+            (LabelValue::Currency, Some(total)) => {
+                format!("{:.0} {}", v * total / 100., conf.currency)
+            }
+            _ => format_percent(percents[position]),
+        };
+        if exposure == Exposure::Holding {
+            let weights = values
+                .iter()
+                .enumerate()
+                .map(|(position, v)| bar_text(position, *v))
+                .collect::<Vec<_>>();
+            let marker = if let (false, Some(palette)) = (conf.heat, &conf.palette) {
+                Marker::new().color_array(
+                    data.iter()
+                        .map(|(k, _)| match &other_holdings {
+                            Some(other) if other == k => "#BFBFBF".to_string(),
+                            _ => label_color(k, palette, conf),
+                        })
+                        .collect(),
+                )
+            } else if conf.heat && known_count > 0 {
+                let baseline = 100. / known_count as f32;
+                Marker::new().color_array(
+                    data.iter()
+                        .map(|(k, v)| {
+                            if *k == conf.unknown_label {
+                                Rgb::new(128, 128, 128)
+                            } else if other_holdings.as_ref() == Some(k) {
+                                Rgb::new(191, 191, 191)
+                            } else {
+                                heat_color(*v, baseline)
+                            }
+                        })
+                        .collect(),
+                )
+            } else {
+                Marker::new()
+            };
+            let (labels, names) = match &conf.holding_tickers {
+                Some(tickers) => (
+                    labels
+                        .iter()
+                        .map(|label| tickers.get(label).unwrap_or(label).clone())
+                        .collect(),
+                    Some(labels),
+                ),
+                None => (labels, None),
+            };
+            // Stack the fixed income part of each holding on top of its equity part. The stack mode
+            // of the layout would apply to every graph, so the equity bar is instead drawn over a
+            // full height fixed income bar in the same position
+            let fixed_income = fixed_income.map(|fixed_income| {
+                data.iter()
+                    .map(|(k, _)| match &other_holdings {
+                        Some(other) if other == k => omitted
+                            .iter()
+                            .filter_map(|(k, _)| fixed_income.get(k))
+                            .sum(),
+                        _ => fixed_income.get(k).copied().unwrap_or_default(),
+                    })
+                    .collect::<Vec<_>>()
+            });
+            let mut trace = match &fixed_income {
+                Some(fixed_income) => {
+                    let equity = values
+                        .iter()
+                        .zip(fixed_income)
+                        .map(|(v, fixed_income)| v - fixed_income)
+                        .collect::<Vec<_>>();
+                    let texts = equity.iter().map(|v| segment_text(*v)).collect();
+                    Bar::new(labels.clone(), equity)
+                        .text_array(texts)
+                        .name("Equity")
+                        .offset_group(exposure.to_string())
+                }
+                None => Bar::new(labels.clone(), values.clone())
+                    .text_array(weights)
+                    .name(""),
+            }
+            .show_legend(false)
+            .hover_info(HoverInfo::None)
+            .marker(marker)
+            .x_axis(format!("x{}", idx + 1))
+            .y_axis(format!("y{}", idx + 1));
+            let hovers = values
+                .iter()
+                .enumerate()
+                .map(|(position, v)| {
+                    let amount =
+                        total.map(|total| format!("{:.0} {}", *v * total / 100., conf.currency));
+                    match (names.as_ref().map(|names| &names[position]), amount) {
+                        (Some(name), Some(amount)) => Some(format!("{}: {}", name, amount)),
+                        (name, amount) => name.cloned().or(amount),
+                    }
+                })
+                .collect::<Option<Vec<_>>>();
+            if let Some(hovers) = &hovers {
+                trace = trace
+                    .hover_info(HoverInfo::Text)
+                    .hover_template_array(hovers.clone());
+            }
+            if let Some(fixed_income) = fixed_income {
+                let texts = fixed_income.iter().map(|v| segment_text(*v)).collect();
+                let mut stacked = Bar::new(labels, values.clone())
+                    .hover_info(HoverInfo::None)
+                    .text_array(texts)
+                    .name(FIXED_INCOME)
+                    .show_legend(false)
+                    .offset_group(exposure.to_string())
+                    .marker(Marker::new().color(NamedColor::DarkOrange))
+                    .x_axis(format!("x{}", idx + 1))
+                    .y_axis(format!("y{}", idx + 1));
+                if let Some(hovers) = &hovers {
+                    stacked = stacked
+                        .hover_info(HoverInfo::Text)
+                        .hover_template_array(hovers.clone());
+                }
+                plot.add_trace(stacked);
+            }
+            plot.add_trace(trace);
+        } else {
+            for (position, (k, v)) in data.into_iter().enumerate() {
+                let mut trace = Bar::new(vec![k.clone()], vec![v])
+                    .name("")
+                    .x_axis(format!("x{}", idx + 1))
+                    .y_axis(format!("y{}", idx + 1))
+                    .text(bar_text(position, v))
+                    .show_legend(false)
+                    .hover_info(HoverInfo::None)
+                    .marker(match &conf.palette {
+                        _ if k.eq(&conf.unknown_label) => Marker::new().color(NamedColor::Gray),
+                        Some(palette) => Marker::new().color(label_color(&k, palette, conf)),
+                        None => Marker::new(),
+                    });
+                if let Some(total) = total {
+                    trace = trace.hover_info(HoverInfo::Text).hover_text(format!(
+                        "{:.0} {}",
+                        v * total / 100.,
+                        conf.currency
+                    ));
+                }
+                plot.add_trace(trace);
+            }
+        }
+    }
+    plot.set_layout(layout);
+    if let PlotlySource::Embed = conf.plotly_source {
+        plot.use_local_plotly();
+    }
+    let mut html = plot.to_html();
+    if !panels.is_empty() {
+        html = html.replacen("</body>", &format!("{}\n</body>", panels.join("\n")), 1);
+    }
+    write_output(&format!("{}.html", output_file), &html)?;
+    if conf.plotly_json {
+        write_output(&format!("{}.plotly.json", output_file), plot.to_json())?;
+    }
+    if conf.image {
+        let format = <ImageFormat as Into<PlotlyImageFormat>>::into(conf.image_format).to_string();
+        let image_file = format!("{}.{}", output_file, format);
+        let figure = serde_json::to_value(&plot)?;
+        // Kaleido panics when its binary is missing or fails, so the export runs on a thread of
+        // its own. A failed image is only a warning as the HTML output has been written already
+        let result = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    Kaleido::new()
+                        .save(
+                            Path::new(&image_file),
+                            &figure,
+                            &format,
+                            1920,
+                            1080,
+                            conf.image_scale,
+                        )
+                        .map_err(|err| err.to_string())
+                })
+                .join()
+        });
+        let reason = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err),
+            Err(panic) => Some(
+                panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| {
+                        panic
+                            .downcast_ref::<&str>()
+                            .map(|reason| reason.to_string())
+                    })
+                    .unwrap_or_else(|| "unknown error".to_string()),
+            ),
+        };
+        if let Some(reason) = reason {
+            event!(
+                Level::WARN,
+                "Failed to save image {}: {}. The HTML output {}.html is still available",
+                image_file,
+                reason,
+                output_file
+            );
+        }
+    }
+    if conf.display {
+        match headless_reason(|name| env::var(name).ok()) {
+            Some(reason) => event!(
+                Level::WARN,
+                "Not opening a browser as {}. The HTML output is {}.html",
+                reason,
+                output_file
+            ),
+            None => plot.show(),
+        }
+    }
+    Ok(())
+}
+
+/// Why no browser can be opened, judging by the environment variables looked up with `var`. An
+/// explicit `BROWSER` is trusted, otherwise CI runs and Linux sessions without a display are
+/// considered headless.
+fn headless_reason(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let set = |name| var(name).is_some_and(|value| !value.is_empty());
+    if set("BROWSER") {
+        return None;
+    }
+    if var("CI").is_some_and(|value| !value.is_empty() && value != "false" && value != "0") {
+        return Some("CI is set".to_string());
+    }
+    if cfg!(target_os = "linux") && !set("DISPLAY") && !set("WAYLAND_DISPLAY") {
+        return Some("neither DISPLAY nor WAYLAND_DISPLAY is set".to_string());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn weights(entries: &[(&str, f32)]) -> Weights {
+        entries
+            .iter()
+            .map(|(label, weight)| (Arc::from(*label), *weight))
+            .collect()
+    }
+
+    fn security(holdings: &[(&str, f32)], sectors: &[(&str, f32)]) -> Security {
+        Security {
+            holding: weights(holdings),
+            sector: weights(sectors),
+            ..Default::default()
+        }
+    }
+
+    fn exposure(
+        securities: &HashMap<String, Security>,
+        exposure: Exposure,
+        isin: &str,
+    ) -> HashMap<String, f32> {
+        exposure_weighted(securities, exposure, isin, 1.)
+    }
+
+    fn exposure_weighted(
+        securities: &HashMap<String, Security>,
+        exposure: Exposure,
+        isin: &str,
+        weight: f32,
+    ) -> HashMap<String, f32> {
+        exposure_partial(securities, exposure, isin, weight, PartialHoldings::Unknown)
+    }
+
+    fn exposure_partial(
+        securities: &HashMap<String, Security>,
+        exposure: Exposure,
+        isin: &str,
+        weight: f32,
+        partial: PartialHoldings,
+    ) -> HashMap<String, f32> {
+        let mut results = HashMap::new();
+        calc_exposure(
+            securities,
+            exposure,
+            isin,
+            weight,
+            partial,
+            None,
+            None,
+            &mut results,
+        )
+        .unwrap();
+        results
+    }
+
+    fn assert_weights(results: &HashMap<String, f32>, expected: &[(&str, f32)]) {
+        assert_eq!(results.len(), expected.len(), "{:?}", results);
+        for (label, weight) in expected {
+            let actual = results.get(*label).copied().unwrap_or_default();
+            assert!(
+                (actual - weight).abs() < 1e-6,
+                "{}: {} != {}",
+                label,
+                actual,
+                weight
+            );
+        }
+    }
+
+    /// A single-stock ETF and a fund of funds holding it next to a regular ETF
+    fn single_stock_securities() -> HashMap<String, Security> {
+        HashMap::from([
+            (
+                "IE000SINGLE1".to_string(),
+                security(&[("NVDA", 1.)], &[("Technology", 1.)]),
+            ),
+            (
+                "IE000BROAD01".to_string(),
+                security(
+                    &[("AAPL", 0.6), ("JPM", 0.4)],
+                    &[("Technology", 0.6), ("Financial Services", 0.4)],
+                ),
+            ),
+            (
+                "EE000FUNDS01".to_string(),
+                security(&[("IE000SINGLE1", 0.25), ("IE000BROAD01", 0.75)], &[]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn single_stock_etf_standalone() {
+        let securities = single_stock_securities();
+        assert_weights(
+            &exposure(&securities, Exposure::Holding, "IE000SINGLE1"),
+            &[("NVDA", 1.)],
+        );
+        assert_weights(
+            &exposure(&securities, Exposure::Sector, "IE000SINGLE1"),
+            &[("Technology", 1.)],
+        );
+    }
+
+    #[test]
+    fn single_stock_etf_in_fund_of_funds() {
+        let securities = single_stock_securities();
+        assert_weights(
+            &exposure(&securities, Exposure::Holding, "EE000FUNDS01"),
+            &[("NVDA", 0.25), ("AAPL", 0.45), ("JPM", 0.3)],
+        );
+        assert_weights(
+            &exposure(&securities, Exposure::Sector, "EE000FUNDS01"),
+            &[("Technology", 0.7), ("Financial Services", 0.3)],
+        );
+    }
+
+    #[test]
+    fn single_holding_in_securities_recurses() {
+        // The only holding is itself a security in the file, so the ETF takes on its exposure
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "IE000SINGLE2".to_string(),
+            security(&[("IE000BROAD01", 1.)], &[]),
+        );
+        assert_weights(
+            &exposure(&securities, Exposure::Holding, "IE000SINGLE2"),
+            &[("AAPL", 0.6), ("JPM", 0.4)],
+        );
+        assert_weights(
+            &exposure(&securities, Exposure::Sector, "IE000SINGLE2"),
+            &[("Technology", 0.6), ("Financial Services", 0.4)],
+        );
+    }
+
+    fn conf() -> Conf {
+        Conf {
+            limit: 20,
+            currency: "€".to_owned(),
+            currency_code: "EUR".to_owned(),
+            display: false,
+            image: false,
+            image_scale: 1.,
+            image_format: ImageFormat::SVG,
+            theme: Theme::Light,
+            unknown_in_title: false,
+            completeness_subtitle: true,
+            heat: false,
+            round_labels: false,
+            other_holdings: false,
+            weight_by: WeightBy::Capital,
+            exposure_basis: ExposureBasis::Reported,
+            reported_completeness: None,
+            min_completeness: None,
+            completeness_banner: false,
+            label_value: LabelValue::Percent,
+            plotly_source: PlotlySource::Cdn,
+            unknown_label: UNKNOWN.to_owned(),
+            other_label: OTHER.to_owned(),
+            partial_holdings: PartialHoldings::Unknown,
+            trace_isin: None,
+            lenient_sectors: false,
+            keep_zero_weights: false,
+            weight_unit: WeightUnit::Percent,
+            normalize_weights: false,
+            rescale_weights: false,
+            empty_as_cash: false,
+            continents: false,
+            plotly_json: false,
+            raw_values: false,
+            holding_tickers: None,
+            separate_files: false,
+            strict_isin: false,
+            stats_panel: false,
+            palette: None,
+            bands: Vec::new(),
+            as_of: "2023-01-01".to_owned(),
+            output_file_name: OsString::from("test"),
+            output_folder: String::new(),
+            round_epsilon: None,
+        }
+    }
+
+    /// Write `contents` to a file in the temp directory that is unique to the test
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pea-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn fixed_income_sleeve_in_sectors() {
+        // A balanced fund of funds with an equity and a bond ETF
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "IE000BONDS01".to_string(),
+            Security {
+                fixed_income: true,
+                ..security(&[], &[("Financial Services", 0.5), ("Utilities", 0.5)])
+            },
+        );
+        securities.insert(
+            "EE000MIXED01".to_string(),
+            security(&[("IE000BROAD01", 0.6), ("IE000BONDS01", 0.4)], &[]),
+        );
+        assert_weights(
+            &exposure(&securities, Exposure::Sector, "EE000MIXED01"),
+            &[
+                ("Technology", 0.36),
+                ("Financial Services", 0.24),
+                (FIXED_INCOME, 0.4),
+            ],
+        );
+    }
+
+    #[test]
+    fn fixed_income_holdings_of_a_balanced_fund() {
+        // Apple is held both as a stock through the equity ETF and as a bond
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "IE000BONDS01".to_string(),
+            Security {
+                fixed_income: true,
+                ..security(&[("AAPL", 0.5), ("Bund 2030", 0.5)], &[])
+            },
+        );
+        securities.insert(
+            "EE000MIXED01".to_string(),
+            security(&[("IE000BROAD01", 0.6), ("IE000BONDS01", 0.4)], &[]),
+        );
+        let portfolio = HashMap::from([("EE000MIXED01".to_string(), 1.)]);
+        let results = fixed_income_holdings(&securities, &portfolio, conf().analysis()).unwrap();
+        assert_weights(
+            &results.into_iter().collect(),
+            &[("AAPL", 20.), ("Bund 2030", 20.)],
+        );
+    }
+
+    #[test]
+    fn tags_are_assigned_at_the_top_level() {
+        let mut securities = single_stock_securities();
+        for (isin, tag) in [("IE000BROAD01", "Core"), ("EE000FUNDS01", "Satellite")] {
+            securities.get_mut(isin).unwrap().tag = weights(&[(tag, 1.)]);
+        }
+        assert_weights(
+            &exposure(&securities, Exposure::Tag, "EE000FUNDS01"),
+            &[("Satellite", 1.)],
+        );
+        assert_weights(&exposure(&securities, Exposure::Tag, "IE000SINGLE1"), &[]);
+    }
+
+    #[test]
+    fn totals_within_float_noise_of_100() {
+        let portfolio = HashMap::from([("EE000NOISE01".to_string(), 1.)]);
+        let securities = HashMap::from([(
+            "EE000NOISE01".to_string(),
+            security(&[("AAPL", 0.7), ("MSFT", 0.300_005)], &[]),
+        )]);
+        let results = analyze_exposure(
+            &securities,
+            &portfolio,
+            Exposure::Holding,
+            conf().analysis(),
+        )
+        .unwrap();
+        assert!(results.iter().all(|(label, _)| label != UNKNOWN));
+        let securities = HashMap::from([(
+            "EE000NOISE01".to_string(),
+            security(&[("AAPL", 0.7), ("MSFT", 0.31)], &[]),
+        )]);
+        assert!(analyze_exposure(
+            &securities,
+            &portfolio,
+            Exposure::Holding,
+            conf().analysis()
+        )
+        .is_err());
+    }
+
+    /// A fund of funds whose listed holdings cover half of it, with sectors of its own
+    fn partial_fund_of_funds() -> HashMap<String, Security> {
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "EE000PARTL1".to_string(),
+            security(
+                &[("IE000BROAD01", 0.5)],
+                &[("Technology", 0.4), ("Health Care", 0.6)],
+            ),
+        );
+        securities
+    }
+
+    #[test]
+    fn partial_holdings_unknown() {
+        let securities = partial_fund_of_funds();
+        let partial = PartialHoldings::Unknown;
+        assert_weights(
+            &exposure_partial(&securities, Exposure::Holding, "EE000PARTL1", 1., partial),
+            &[("AAPL", 0.3), ("JPM", 0.2)],
+        );
+    }
+
+    #[test]
+    fn partial_holdings_scale() {
+        let mut securities = partial_fund_of_funds();
+        let partial = PartialHoldings::Scale;
+        assert_weights(
+            &exposure_partial(&securities, Exposure::Holding, "EE000PARTL1", 1., partial),
+            &[("AAPL", 0.6), ("JPM", 0.4)],
+        );
+        securities.get_mut("EE000PARTL1").unwrap().sector.clear();
+        assert_weights(
+            &exposure_partial(&securities, Exposure::Sector, "EE000PARTL1", 0.5, partial),
+            &[("Technology", 0.3), ("Financial Services", 0.2)],
+        );
+        // Fully listed holdings are left as they are
+        assert_weights(
+            &exposure_partial(&securities, Exposure::Holding, "EE000FUNDS01", 1., partial),
+            &[("NVDA", 0.25), ("AAPL", 0.45), ("JPM", 0.3)],
+        );
+    }
+
+    #[test]
+    fn partial_holdings_fallback() {
+        let securities = partial_fund_of_funds();
+        let partial = PartialHoldings::Fallback;
+        // The unlisted half takes the sectors of the fund itself
+        assert_weights(
+            &exposure_partial(&securities, Exposure::Sector, "EE000PARTL1", 1., partial),
+            &[
+                ("Technology", 0.3 + 0.2),
+                ("Financial Services", 0.2),
+                ("Health Care", 0.3),
+            ],
+        );
+        // Holdings have no data of the fund to fall back on
+        assert_weights(
+            &exposure_partial(&securities, Exposure::Holding, "EE000PARTL1", 1., partial),
+            &[("AAPL", 0.3), ("JPM", 0.2)],
+        );
+    }
+
+    #[test]
+    fn cached_expansion_matches_uncached() {
+        // Two funds of funds share the nested ETFs, one of them also through another fund of funds
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "EE000FUNDS02".to_string(),
+            security(
+                &[
+                    ("IE000SINGLE1", 0.1),
+                    ("EE000FUNDS01", 0.5),
+                    ("IE000BROAD01", 0.3),
+                ],
+                &[],
+            ),
+        );
+        let mut cache = ExpansionCache::new();
+        for exposure in [Exposure::Holding, Exposure::Sector] {
+            for (isin, weight) in [("EE000FUNDS01", 0.4), ("EE000FUNDS02", 0.6)] {
+                let mut cached = HashMap::new();
+                calc_exposure(
+                    &securities,
+                    exposure,
+                    isin,
+                    weight,
+                    PartialHoldings::Unknown,
+                    None,
+                    Some(&mut cache),
+                    &mut cached,
+                )
+                .unwrap();
+                let uncached = exposure_weighted(&securities, exposure, isin, weight);
+                let expected = uncached
+                    .iter()
+                    .map(|(label, weight)| (label.as_str(), *weight))
+                    .collect::<Vec<_>>();
+                assert_weights(&cached, &expected);
+            }
+        }
+        assert!(cache.contains_key(&("IE000SINGLE1".to_string(), Exposure::Sector)));
+    }
+
+    #[test]
+    fn write_output_replaces_file() {
+        let path = temp_file("output.html", "old");
+        let path = path.to_string_lossy();
+        write_output(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&*path).unwrap(), "new");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        fs::remove_file(&*path).unwrap();
+    }
+
+    #[test]
+    fn write_output_names_path() {
+        let path = env::temp_dir()
+            .join("pea-missing-folder")
+            .join("output.html");
+        let err = write_output(&path.to_string_lossy(), "new")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("pea-missing-folder"), "{}", err);
+    }
+
+    #[test]
+    fn dividend_metrics_cover_securities_with_data() {
+        let mut securities = single_stock_securities();
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.25),
+            ("IE000BROAD01".to_string(), 0.75),
+        ]);
+        assert_eq!(calculate_div_growth(&securities, &portfolio).unwrap(), None);
+        securities.get_mut("IE000SINGLE1").unwrap().div_growth = Some(10.);
+        securities.get_mut("IE000BROAD01").unwrap().div_growth = Some(6.);
+        securities.get_mut("IE000BROAD01").unwrap().payout_ratio = Some(40.);
+        assert_eq!(
+            calculate_div_growth(&securities, &portfolio).unwrap(),
+            Some(7.)
+        );
+        assert_eq!(
+            calculate_payout_ratio(&securities, &portfolio).unwrap(),
+            Some(40.)
+        );
+    }
+
+    #[test]
+    fn headless_environments() {
+        let headless = |vars: &[(&str, &str)]| {
+            let vars = HashMap::<_, _>::from_iter(vars.iter().copied());
+            headless_reason(|name| vars.get(name).map(|value| value.to_string()))
+        };
+        assert!(headless(&[("CI", "true"), ("DISPLAY", ":0")]).is_some());
+        assert!(headless(&[("CI", "true"), ("BROWSER", "firefox")]).is_none());
+        assert!(headless(&[("CI", "false"), ("DISPLAY", ":0")]).is_none());
+        if cfg!(target_os = "linux") {
+            assert!(headless(&[]).is_some());
+            assert!(headless(&[("WAYLAND_DISPLAY", "wayland-0")]).is_none());
+        }
+    }
+
+    #[test]
+    fn largest_remainder_adds_up() {
+        let rounded = largest_remainder(&[33.333, 33.333, 33.334]);
+        assert_eq!(rounded, [33.33, 33.33, 33.34]);
+        let rounded = largest_remainder(&[60.004, 19.998, 19.998]);
+        assert_eq!(rounded, [60., 20., 20.]);
+    }
+
+    #[test]
+    fn reconcile_counts_mismatches() {
+        let securities = single_stock_securities();
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.6),
+            ("IE000BROAD01".to_string(), 0.4),
+        ]);
+        let reference = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.598),
+            ("IE000BROAD01".to_string(), 0.302),
+            ("EE000FUNDS01".to_string(), 0.1),
+        ]);
+        assert_eq!(reconcile(&securities, &portfolio, &reference, 0.5), 2);
+        assert_eq!(reconcile(&securities, &portfolio, &portfolio, 0.), 0);
+    }
+
+    #[test]
+    fn markdown_tables() {
+        let data = vec![(
+            Exposure::Holding,
+            vec![("A|B".to_string(), 60.), (UNKNOWN.to_string(), 40.)],
+        )];
+        let metrics = Metrics {
+            ter: 0.2,
+            net_ter: None,
+            total_cost: None,
+            duration: None,
+            pe: None,
+            div_growth: None,
+            payout_ratio: None,
+        };
+        let path = temp_file("report.md", "");
+        write_markdown(
+            &path.to_string_lossy(),
+            &data,
+            Some(1000.),
+            &metrics,
+            &conf(),
+        )
+        .unwrap();
+        let markdown = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(
+            markdown.starts_with("# Asset exposure for "),
+            "{}",
+            markdown
+        );
+        assert!(markdown.contains("TER 0.200%"), "{}", markdown);
+        assert!(
+            markdown.contains("| A\\|B | 60.00% | 600.00 € |\n"),
+            "{}",
+            markdown
+        );
+    }
+
+    #[cfg(feature = "xlsx")]
+    #[test]
+    fn xlsx_workbook() {
+        use std::io::Read;
+
+        let data = vec![(
+            Exposure::Holding,
+            vec![("A&B".to_string(), 60.), (UNKNOWN.to_string(), 40.)],
+        )];
+        let metrics = Metrics {
+            ter: 0.2,
+            net_ter: None,
+            total_cost: None,
+            duration: None,
+            pe: None,
+            div_growth: None,
+            payout_ratio: None,
+        };
+        let conf = Conf {
+            output_file_name: OsString::from("2024"),
+            ..conf()
+        };
+        let path = temp_file("report.xlsx", "");
+        write_xlsx(&path.to_string_lossy(), &data, Some(1000.), &metrics, &conf).unwrap();
+        let mut workbook = zip::ZipArchive::new(fs::File::open(&path).unwrap()).unwrap();
+        let mut part = |name: &str| {
+            let mut contents = String::new();
+            workbook
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        let sheets = part("xl/workbook.xml");
+        let summary = part("xl/worksheets/sheet1.xml");
+        let holdings = part("xl/worksheets/sheet2.xml");
+        fs::remove_file(&path).unwrap();
+        assert!(
+            sheets.contains(r#"<sheet name="Summary" sheetId="1""#),
+            "{}",
+            sheets
+        );
+        assert!(sheets.contains(r#"sheetId="2""#) && !sheets.contains(r#"sheetId="3""#));
+        // The portfolio name stays a string even though it looks like a number
+        assert!(
+            summary.contains(r#"<c r="B1" t="inlineStr"><is><t>2024</t></is></c>"#),
+            "{}",
+            summary
+        );
+        assert!(holdings.contains(r#"<c r="A2" t="inlineStr"><is><t>A&amp;B</t></is></c>"#));
+        assert!(holdings.contains(r#"<c r="B2"><v>60</v></c><c r="C2"><v>600</v></c>"#));
+    }
+
+    #[test]
+    fn sector_divergence_of_a_fund_of_funds() {
+        let mut securities = single_stock_securities();
+        // Looked through, the fund is 70% Technology and 30% Financial Services
+        securities.get_mut("EE000FUNDS01").unwrap().sector =
+            weights(&[("Technology", 0.7), ("Financial Services", 0.3)]);
+        let (divergence, covered, _) =
+            sector_divergence(&securities, &securities["EE000FUNDS01"], conf().analysis())
+                .unwrap()
+                .unwrap();
+        assert!(divergence.abs() < 1e-3, "{}", divergence);
+        assert_eq!(covered, 1.);
+        securities.get_mut("EE000FUNDS01").unwrap().sector =
+            weights(&[("Technology", 0.4), ("Energy", 0.6)]);
+        let (divergence, _, (sector, own, through)) =
+            sector_divergence(&securities, &securities["EE000FUNDS01"], conf().analysis())
+                .unwrap()
+                .unwrap();
+        assert!((divergence - 60.).abs() < 1e-3, "{}", divergence);
+        assert_eq!(sector, "Energy");
+        assert!((own - 60.).abs() < 1e-3 && through == 0.);
+        // Funds without holdings in the securities are not compared
+        assert!(
+            sector_divergence(&securities, &securities["IE000BROAD01"], conf().analysis())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn sparse_exposures_below_minimum() {
+        let data = vec![
+            (
+                Exposure::Region,
+                vec![("Europe".to_string(), 60.), (UNKNOWN.to_string(), 40.)],
+            ),
+            (Exposure::Sector, vec![("Energy".to_string(), 100.)]),
+        ];
+        let mut conf = conf();
+        assert!(sparse_exposures(&data, &conf).is_empty());
+        conf.min_completeness = Some(75.);
+        assert_eq!(
+            sparse_exposures(&data, &conf),
+            vec![(Exposure::Region, 60.)]
+        );
+        conf.min_completeness = Some(60.);
+        assert!(sparse_exposures(&data, &conf).is_empty());
+    }
+
+    #[test]
+    fn risk_weights_scale_by_volatility() {
+        let mut securities = single_stock_securities();
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.5),
+            ("IE000BROAD01".to_string(), 0.5),
+        ]);
+        securities.get_mut("IE000SINGLE1").unwrap().volatility = Some(30.);
+        assert!(risk_weights(&securities, &portfolio).is_err());
+        securities.get_mut("IE000BROAD01").unwrap().volatility = Some(10.);
+        let risks = risk_weights(&securities, &portfolio).unwrap();
+        assert_weights(&risks, &[("IE000SINGLE1", 0.75), ("IE000BROAD01", 0.25)]);
+    }
+
+    #[test]
+    fn rebase_exposure_on_classified() {
+        let mut results = vec![
+            ("Europe".to_string(), 30.),
+            (UNKNOWN.to_string(), 40.),
+            ("Asia".to_string(), 30.),
+        ];
+        assert_eq!(rebase_on_classified(&mut results, UNKNOWN), 60.);
+        assert_eq!(
+            results,
+            vec![("Europe".to_string(), 50.), ("Asia".to_string(), 50.)]
+        );
+        let mut unclassified = vec![(UNKNOWN.to_string(), 100.)];
+        assert_eq!(rebase_on_classified(&mut unclassified, UNKNOWN), 0.);
+        assert_eq!(unclassified, vec![(UNKNOWN.to_string(), 100.)]);
+    }
+
+    #[test]
+    fn filter_tag_keeps_the_sleeve() {
+        let mut securities = single_stock_securities();
+        securities.get_mut("IE000BROAD01").unwrap().tag = HashMap::from([("Core".into(), 1.)]);
+        let portfolio = HashMap::from([
+            ("IE000BROAD01".to_string(), 0.25),
+            ("EE000FUNDS01".to_string(), 0.75),
+        ]);
+        let (total, portfolio) = filter_tag((Some(1000.), portfolio), &securities, "Core").unwrap();
+        assert_eq!(total, Some(250.));
+        assert_eq!(portfolio, HashMap::from([("IE000BROAD01".to_string(), 1.)]));
+        assert!(filter_tag((None, portfolio), &securities, "Satellite").is_err());
+    }
+
+    #[test]
+    fn drop_small_positions_scales_the_rest() {
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.005),
+            ("IE000BROAD01".to_string(), 0.995),
+        ]);
+        let (total, portfolio) = drop_small_positions((Some(1000.), portfolio), 1.).unwrap();
+        assert_eq!(total, Some(995.));
+        assert_eq!(portfolio, HashMap::from([("IE000BROAD01".to_string(), 1.)]));
+        assert!(drop_small_positions((None, portfolio), 101.).is_err());
+    }
+
+    #[test]
+    fn rebalance_without_selling() {
+        let portfolio = HashMap::from([
+            ("IE000BROAD01".to_string(), 0.5),
+            ("IE000SINGLE1".to_string(), 0.25),
+            ("EE000FUNDS01".to_string(), 0.25),
+        ]);
+        let target = HashMap::from([
+            ("IE000BROAD01".to_string(), 0.5),
+            ("IE000SINGLE1".to_string(), 0.5),
+            ("EE000FUNDS01".to_string(), 0.),
+        ]);
+        let trades = calculate_rebalance(&portfolio, 1000., &target, false);
+        assert_eq!(
+            trades,
+            vec![
+                ("EE000FUNDS01".to_string(), 250., 0., -250.),
+                ("IE000BROAD01".to_string(), 500., 0.5, 0.),
+                ("IE000SINGLE1".to_string(), 250., 0.5, 250.),
+            ]
+        );
+        let trades = calculate_rebalance(&portfolio, 1000., &target, true);
+        assert!(
+            trades.iter().all(|(_, _, _, trade)| *trade >= 0.),
+            "{:?}",
+            trades
+        );
+        assert_eq!(trades[0], ("EE000FUNDS01".to_string(), 250., 0., 0.));
+    }
+
+    #[test]
+    fn cyclic_holdings_are_an_error() {
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "EE000CYCLE01".to_string(),
+            security(&[("EE000CYCLE02", 0.5), ("IE000BROAD01", 0.5)], &[]),
+        );
+        securities.insert(
+            "EE000CYCLE02".to_string(),
+            security(&[("EE000CYCLE01", 1.)], &[]),
+        );
+        for cache in [None, Some(&mut ExpansionCache::new())] {
+            let err = calc_exposure(
+                &securities,
+                Exposure::Holding,
+                "EE000CYCLE01",
+                1.,
+                PartialHoldings::Unknown,
+                None,
+                cache,
+                &mut HashMap::new(),
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Securities hold each other in a cycle: EE000CYCLE01 -> EE000CYCLE02 -> EE000CYCLE01"
+            );
+        }
+        let portfolio = HashMap::from([("EE000CYCLE02".to_string(), 1.)]);
+        assert!(
+            analyze_exposure(&securities, &portfolio, Exposure::Sector, conf().analysis()).is_err()
+        );
+    }
+
+    #[test]
+    fn fund_names() {
+        assert!(looks_like_fund("iShares Core MSCI World UCITS ETF"));
+        assert!(looks_like_fund("Vanguard FTSE All-World"));
+        assert!(!looks_like_fund("Northern Trust Corp"));
+        assert!(!looks_like_fund("Index Holdings"));
+        assert!(!looks_like_fund("US0378331005"));
+        assert!(!looks_like_fund("Fundamental Corp"));
+    }
+
+    #[test]
+    fn deepest_chain_follows_nested_funds() {
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "EE000FUNDS02".to_string(),
+            security(&[("EE000FUNDS01", 0.5), ("IE000BROAD01", 0.5)], &[]),
+        );
+        // IE000BROAD01 and IE000SINGLE1 are equally deep, the first ISIN wins
+        assert_eq!(
+            deepest_chain(&securities, "EE000FUNDS02"),
+            ["EE000FUNDS02", "EE000FUNDS01", "IE000BROAD01"]
+        );
+        assert_eq!(deepest_chain(&securities, "IE000BROAD01"), ["IE000BROAD01"]);
+    }
+
+    #[test]
+    fn parse_number_rejects_non_finite() {
+        assert_eq!(parse_number("NaN"), None);
+        assert_eq!(parse_number(&"1".repeat(50)), None);
+        assert_eq!(parse_number("1,234.5"), Some(1234.5));
+    }
+
+    #[test]
+    fn parse_number_strips_only_decorations() {
+        assert_eq!(parse_number("€ 1.234,50"), Some(1234.5));
+        assert_eq!(parse_number("1,234 EUR"), Some(1234.));
+        assert_eq!(parse_number("-$12.5"), Some(-12.5));
+        assert_eq!(parse_number("3,5 %"), Some(3.5));
+        assert_eq!(parse_number("1e5"), None);
+        assert_eq!(parse_number("12abc"), None);
+    }
+
+    #[test]
+    fn parse_weight_strips_units() {
+        assert_eq!(parse_weight("3.5"), Some((3.5, false)));
+        assert_eq!(parse_weight(" 3.5 "), Some((3.5, false)));
+        assert_eq!(parse_weight("3.5 %"), Some((3.5, true)));
+        assert_eq!(parse_weight("3.5pct"), Some((3.5, true)));
+        assert_eq!(parse_weight("3,5 %"), Some((3.5, true)));
+        assert_eq!(parse_weight(""), None);
+        assert_eq!(parse_weight("n/a"), None);
+    }
+
+    #[test]
+    fn securities_with_non_finite_weight() {
+        for value in ["NaN", "inf", "-infinity"] {
+            let path = temp_file(
+                &format!("securities-{}.csv", value),
+                &format!(
+                    "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n\
+                     IE000BROAD01,Broad,0.2,Apple,{},Technology,100,United States,100,Americas,100\n",
+                    value
+                ),
+            );
+            let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf());
+            fs::remove_file(&path).unwrap();
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains("IE000BROAD01"), "{}", err);
+            assert!(err.contains("HoldingWeight"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn securities_with_zero_and_negative_weights() {
+        let path = temp_file(
+            "securities-zero.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,100,Technology,100,United States,100,Americas,100\n\
+             ,,,,,Energy,0,,,,\n\
+             ,,,,,Utilities,-5,,,,\n",
+        );
+        let sectors = |conf: &Conf| {
+            let securities =
+                parse_securities(Some(path.to_string_lossy().into()), None, conf).unwrap();
+            let mut sectors = securities["IE000BROAD01"]
+                .get_exposure(Exposure::Sector)
+                .keys()
+                .map(|sector| sector.to_string())
+                .collect::<Vec<_>>();
+            sectors.sort();
+            sectors
+        };
+        let mut conf = conf();
+        assert_eq!(sectors(&conf), ["Technology"]);
+        conf.keep_zero_weights = true;
+        assert_eq!(sectors(&conf), ["Energy", "Technology"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn securities_with_non_finite_ter() {
+        let path = temp_file(
+            "securities-ter.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n\
+             IE000BROAD01,Broad,NaN,Apple,100,Technology,100,United States,100,Americas,100\n",
+        );
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf());
+        fs::remove_file(&path).unwrap();
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.contains("IE000BROAD01") && err.contains("TER"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn bundled_securities_without_files() {
+        let securities = parse_securities(None, None, &conf()).unwrap();
+        assert!(securities.contains_key("IE0031442068"));
+        assert!(securities
+            .values()
+            .all(|security| !security.holding.is_empty()));
+    }
+
+    #[test]
+    fn sectors_per_holding() {
+        let path = temp_file(
+            "securities-holding-sectors.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,Country,CountryWeight,Region,RegionWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,40,Technology,United States,100,Americas,100\n\
+             ,,,Microsoft,30,Technology,,,,\n\
+             ,,,JPMorgan,20,Financial Services,,,,\n\
+             ,,,Unlisted,10,,,,,\n",
+        );
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf());
+        fs::remove_file(&path).unwrap();
+        let securities = result.unwrap();
+        assert_weights(
+            &exposure(&securities, Exposure::Sector, "IE000BROAD01"),
+            &[("Technology", 0.7), ("Financial Services", 0.2)],
+        );
+    }
+
+    #[test]
+    fn repeated_labels_are_kept_once() {
+        let path = temp_file(
+            "securities-repeated.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,40,Technology,50,United States,100,Americas,100\n\
+             ,,,Apple,60,Technology,20,,,,\n\
+             ,,,,,Information Technology,10,,,,\n",
+        );
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf());
+        fs::remove_file(&path).unwrap();
+        let securities = result.unwrap();
+        assert_weights(
+            &exposure(&securities, Exposure::Holding, "IE000BROAD01"),
+            &[("Apple", 0.6)],
+        );
+        // Synonyms of a sector add up
+        assert_weights(
+            &exposure(&securities, Exposure::Sector, "IE000BROAD01"),
+            &[("Technology", 0.3)],
+        );
+    }
+
+    #[test]
+    fn unknown_sectors_without_weight_are_an_error() {
+        let path = temp_file(
+            "securities-unknown-sector.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,100,Technology,100,United States,100,Americas,100\n\
+             ,,,,,Gadgets,0,,,,\n",
+        );
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf());
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn securities_are_send() {
+        fn send<T: Send>() {}
+        send::<HashMap<String, Security>>();
+    }
+
+    #[test]
+    fn currency_hedge_moves_to_the_portfolio_currency() {
+        let path = temp_file(
+            "securities-hedge.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,Country,CountryWeight,Region,RegionWeight,CurrencyHedge\n\
+             IE000BROAD01,Broad,0.2,Apple,100,Technology,United States,60,Americas,60,50\n\
+             ,,,,,,Germany,40,Europe,40,\n",
+        );
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf());
+        let securities = result.unwrap();
+        assert_weights(
+            &exposure(&securities, Exposure::CurrencyBloc, "IE000BROAD01"),
+            &[("Dollar Bloc", 0.3), ("Euro Bloc", 0.7)],
+        );
+        // Without a bloc for the portfolio currency the hedge is left out
+        let conf = Conf {
+            currency_code: "XYZ".to_owned(),
+            ..conf()
+        };
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf);
+        fs::remove_file(&path).unwrap();
+        assert_weights(
+            &exposure(&result.unwrap(), Exposure::CurrencyBloc, "IE000BROAD01"),
+            &[("Dollar Bloc", 0.6), ("Euro Bloc", 0.4)],
+        );
+    }
+
+    #[test]
+    fn portfolio_with_zero_total() {
+        let securities = single_stock_securities();
+        let path = temp_file(
+            "portfolio-zero.csv",
+            "ISIN,Amount\nIE000SINGLE1,0\nIE000BROAD01,0\n",
+        );
+        let result = parse_portfolio(&path.to_string_lossy(), &securities, &conf());
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn ter_with_non_finite_value() {
+        let mut securities = single_stock_securities();
+        securities.get_mut("IE000BROAD01").unwrap().ter = f32::NAN;
+        let portfolio = HashMap::from([("IE000BROAD01".to_string(), 1.)]);
+        let err = calculate_ter(&securities, &portfolio)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("IE000BROAD01"), "{}", err);
+    }
+
+    #[test]
+    fn net_ter_without_non_finite_income() {
+        let mut securities = single_stock_securities();
+        for (isin, ter, income) in [("IE000SINGLE1", 0.3, 0.1), ("IE000BROAD01", 0.2, f32::NAN)] {
+            let security = securities.get_mut(isin).unwrap();
+            security.ter = ter;
+            security.sec_lending_income = Some(income);
+        }
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.5),
+            ("IE000BROAD01".to_string(), 0.5),
+        ]);
+        let net_ter = calculate_net_ter(&securities, &portfolio).unwrap().unwrap();
+        assert!((net_ter - 0.2).abs() < 1e-6, "{}", net_ter);
+    }
+
+    #[test]
+    fn total_cost_with_transaction_costs() {
+        let mut securities = single_stock_securities();
+        for (isin, ter, transaction_cost) in [
+            ("IE000SINGLE1", 0.3, Some(0.1)),
+            ("IE000BROAD01", 0.2, None),
+            ("EE000FUNDS01", 0.4, Some(f32::INFINITY)),
+        ] {
+            let security = securities.get_mut(isin).unwrap();
+            security.ter = ter;
+            security.transaction_cost = transaction_cost;
+        }
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.5),
+            ("IE000BROAD01".to_string(), 0.25),
+            ("EE000FUNDS01".to_string(), 0.25),
+        ]);
+        let total_cost = calculate_total_cost(&securities, &portfolio)
+            .unwrap()
+            .unwrap();
+        // The non-finite transaction cost leaves the TER of its fund
+        assert!((total_cost - 0.35).abs() < 1e-6, "{}", total_cost);
+        let portfolio = HashMap::from([("IE000BROAD01".to_string(), 1.)]);
+        assert_eq!(calculate_total_cost(&securities, &portfolio).unwrap(), None);
+    }
+
+    #[test]
+    fn pe_without_invalid_values() {
+        let mut securities = single_stock_securities();
+        for (isin, pe) in [
+            ("IE000SINGLE1", 20.),
+            ("IE000BROAD01", 0.),
+            ("EE000FUNDS01", f32::NAN),
+        ] {
+            securities.get_mut(isin).unwrap().pe = Some(pe);
+        }
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.5),
+            ("IE000BROAD01".to_string(), 0.25),
+            ("EE000FUNDS01".to_string(), 0.25),
+        ]);
+        assert_eq!(calculate_pe(&securities, &portfolio).unwrap(), Some(20.));
+    }
+
+    #[test]
+    fn pe_as_harmonic_mean() {
+        let mut securities = single_stock_securities();
+        securities.get_mut("IE000SINGLE1").unwrap().pe = Some(10.);
+        securities.get_mut("IE000BROAD01").unwrap().pe = Some(40.);
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.5),
+            ("IE000BROAD01".to_string(), 0.5),
+        ]);
+        // The arithmetic mean would be 25
+        let pe = calculate_pe(&securities, &portfolio).unwrap().unwrap();
+        assert!((pe - 16.).abs() < 1e-4, "{}", pe);
+    }
+
+    #[test]
+    fn duration_and_pe_in_history() {
+        let data = vec![(Exposure::Holding, vec![("A".to_string(), 100.)])];
+        let metrics = Metrics {
+            ter: 0.2,
+            net_ter: None,
+            total_cost: None,
+            duration: Some(6.5),
+            pe: Some(16.),
+            div_growth: None,
+            payout_ratio: None,
+        };
+        for raw_values in [false, true] {
+            let path = temp_file("history-metrics.jsonl", "");
+            let conf = Conf {
+                raw_values,
+                ..conf()
+            };
+            append_history(&path.to_string_lossy(), &data, None, &metrics, &conf).unwrap();
+            let history = fs::read_to_string(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            let entry = serde_json::from_str::<serde_json::Value>(&history).unwrap();
+            assert_eq!(entry["duration"], serde_json::json!(6.5), "{}", history);
+            assert_eq!(entry["pe"], serde_json::json!(16.), "{}", history);
+        }
+    }
+
+    #[test]
+    fn exposure_with_non_finite_weight() {
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "IE000BROKEN1".to_string(),
+            security(&[("AAPL", f32::NAN)], &[("Technology", 1.)]),
+        );
+        let portfolio = HashMap::from([("IE000BROKEN1".to_string(), 1.)]);
+        assert!(analyze_exposure(
+            &securities,
+            &portfolio,
+            Exposure::Holding,
+            conf().analysis()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn exposure_over_100_percent() {
+        let mut securities = single_stock_securities();
+        securities.insert(
+            "IE000BROKEN1".to_string(),
+            security(&[("AAPL", 0.8), ("MSFT", 0.8)], &[("Technology", 1.)]),
+        );
+        let portfolio = HashMap::from([("IE000BROKEN1".to_string(), 1.)]);
+        let err = analyze_exposure(
+            &securities,
+            &portfolio,
+            Exposure::Holding,
+            conf().analysis(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("> 100%"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_portfolios() {
+        let securities = single_stock_securities();
+        for (name, contents) in [
+            ("no-allocation", "ISIN,Shares\nIE000SINGLE1,10\n"),
+            ("no-isin", "Name,Weight\nSingle,100\n"),
+            ("unknown-ticker", "Ticker,Weight\nNOPE,100\n"),
+            ("bad-weight", "ISIN,Weight\nIE000SINGLE1,lots\n"),
+            ("no-price", "ISIN,Shares,Price\nIE000SINGLE1,10,\n"),
+            (
+                "duplicate-isin",
+                "ISIN,Amount\nIE000SINGLE1,100\nIE000SINGLE1,50\n",
+            ),
+            (
+                "mixed-intent",
+                "ISIN,Weight,Amount\nIE000SINGLE1,50,\nIE000BROAD01,,1000\n",
+            ),
+            ("over-weight", "ISIN,Weight\nIE000SINGLE1,150\n"),
+            ("short-row", "ISIN,Weight\nIE000SINGLE1\n"),
+            ("empty", ""),
+        ] {
+            let path = temp_file(&format!("portfolio-{}.csv", name), contents);
+            let result = parse_portfolio(&path.to_string_lossy(), &securities, &conf());
+            fs::remove_file(&path).unwrap();
+            assert!(result.is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn portfolio_with_fractional_shares() {
+        let securities = single_stock_securities();
+        let path = temp_file(
+            "portfolio-shares.csv",
+            "ISIN,Shares,Price\nIE000SINGLE1,2.5,40\nIE000BROAD01,0.125,2400\n",
+        );
+        let (total, portfolio) =
+            parse_portfolio(&path.to_string_lossy(), &securities, &conf()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(total, Some(400.));
+        assert_weights(
+            &portfolio,
+            &[("IE000SINGLE1", 0.25), ("IE000BROAD01", 0.75)],
+        );
+    }
+
+    #[test]
+    fn find_date_in_non_ascii_text() {
+        assert_eq!(
+            find_date("portföljen_2023-01-31").as_deref(),
+            Some("2023-01-31")
+        );
+        assert_eq!(find_date("åäö"), None);
+    }
+
+    #[test]
+    fn reconcile_total_within_epsilon() {
+        let mut results = vec![("A".to_string(), 60.), ("B".to_string(), 39.99)];
+        assert_eq!(reconcile_total(&mut results, 99.99, 0.05), 100.);
+        assert!((results[0].1 - 60.006).abs() < 1e-3, "{:?}", results);
+        assert!((results[1].1 - 39.994).abs() < 1e-3, "{:?}", results);
+        // A residual above the epsilon is left for the unknown share
+        let mut results = vec![("A".to_string(), 90.)];
+        assert_eq!(reconcile_total(&mut results, 90., 0.05), 90.);
+        assert_eq!(results[0].1, 90.);
+    }
+
+    #[test]
+    fn format_percent_without_negative_zero() {
+        assert_eq!(format_percent(-0.001), "0.00%");
+        assert_eq!(format_percent(0.004), "0.00%");
+        assert_eq!(format_percent(-0.01), "-0.01%");
+        assert_eq!(format_percent(12.345), "12.35%");
+    }
+
+    #[test]
+    fn isin_check_digits() {
+        assert_eq!(validate_isin("US0378331005"), Ok(()));
+        assert_eq!(validate_isin("IE00B4L5Y983"), Ok(()));
+        assert!(validate_isin("US0378331006").is_err());
+        assert!(validate_isin("US037833100").is_err());
+        assert!(validate_isin("us0378331005").is_err());
+    }
+
+    #[test]
+    fn fingerprint_ignores_order_and_noise() {
+        let data = vec![
+            (
+                Exposure::Sector,
+                vec![("Technology".to_string(), 60.), (UNKNOWN.to_string(), 40.)],
+            ),
+            (Exposure::Region, vec![("Europe".to_string(), 100.)]),
+        ];
+        let reordered = vec![
+            (Exposure::Region, vec![("Europe".to_string(), 100.001)]),
+            (
+                Exposure::Sector,
+                vec![(UNKNOWN.to_string(), 40.), ("Technology".to_string(), 60.)],
+            ),
+        ];
+        assert_eq!(fingerprint(&data), fingerprint(&reordered));
+        let changed = vec![(Exposure::Region, vec![("Europe".to_string(), 99.)])];
+        assert_ne!(fingerprint(&data), fingerprint(&changed));
+    }
+
+    #[test]
+    fn add_position_renormalizes() {
+        let securities = single_stock_securities();
+        let portfolio = HashMap::from([("IE000BROAD01".to_string(), 1.)]);
+        let (total, added) = add_position(
+            (Some(1000.), portfolio.clone()),
+            &securities,
+            "IE000SINGLE1:1000",
+            &conf(),
+        )
+        .unwrap();
+        assert_eq!(total, Some(2000.));
+        assert_eq!(added["IE000BROAD01"], 0.5);
+        assert_eq!(added["IE000SINGLE1"], 0.5);
+        // Portfolios with weights take the amount as a weight in percent
+        let (total, added) = add_position(
+            (None, portfolio.clone()),
+            &securities,
+            "IE000BROAD01:25",
+            &conf(),
+        )
+        .unwrap();
+        assert_eq!(total, None);
+        assert_eq!(added, HashMap::from([("IE000BROAD01".to_string(), 1.)]));
+        assert!(add_position(
+            (None, portfolio.clone()),
+            &securities,
+            "IE000SINGLE1:-5",
+            &conf()
+        )
+        .is_err());
+        // A quarter of the whole in the weight unit of the portfolio
+        let conf = Conf {
+            weight_unit: WeightUnit::Fraction,
+            ..conf()
+        };
+        let (_, added) =
+            add_position((None, portfolio), &securities, "IE000SINGLE1:0.25", &conf).unwrap();
+        assert_eq!(added["IE000BROAD01"], 0.8);
+        assert_eq!(added["IE000SINGLE1"], 0.2);
+    }
+
+    #[test]
+    fn exclude_isins_with_and_without_renormalizing() {
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.2),
+            ("IE000BROAD01".to_string(), 0.8),
+        ]);
+        let excluded = vec!["IE000SINGLE1".to_string()];
+        let (total, kept) =
+            exclude_isins((Some(1000.), portfolio.clone()), &excluded, true).unwrap();
+        assert_eq!(total, Some(800.));
+        assert_eq!(kept, HashMap::from([("IE000BROAD01".to_string(), 1.)]));
+        let (total, kept) = exclude_isins((Some(1000.), portfolio), &excluded, false).unwrap();
+        assert_eq!(total, Some(1000.));
+        assert_eq!(kept, HashMap::from([("IE000BROAD01".to_string(), 0.8)]));
+        assert!(exclude_isins((None, kept), &["IE000BROAD01".to_string()], true).is_err());
+    }
+
+    #[test]
+    fn total_cost_adds_transaction_costs() {
+        let mut securities = single_stock_securities();
+        securities.get_mut("IE000SINGLE1").unwrap().ter = 0.2;
+        securities.get_mut("IE000BROAD01").unwrap().ter = 0.1;
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.5),
+            ("IE000BROAD01".to_string(), 0.5),
+        ]);
+        assert_eq!(calculate_total_cost(&securities, &portfolio).unwrap(), None);
+        securities.get_mut("IE000BROAD01").unwrap().transaction_cost = Some(0.1);
+        let cost = calculate_total_cost(&securities, &portfolio)
+            .unwrap()
+            .unwrap();
+        assert!((cost - 0.2).abs() < 1e-6, "{}", cost);
+    }
+
+    #[test]
+    fn effective_count_of_classified_entries() {
+        let even = vec![
+            ("A".to_string(), 25.),
+            ("B".to_string(), 25.),
+            (UNKNOWN.to_string(), 50.),
+        ];
+        assert!((effective_count(&even, UNKNOWN).unwrap() - 2.).abs() < 1e-4);
+        let skewed = vec![("A".to_string(), 90.), ("B".to_string(), 10.)];
+        let count = effective_count(&skewed, UNKNOWN).unwrap();
+        assert!(count > 1. && count < 2., "{}", count);
+        assert_eq!(
+            effective_count(&[(UNKNOWN.to_string(), 100.)], UNKNOWN),
+            None
+        );
+    }
+
+    #[test]
+    fn merge_portfolios_by_value() {
+        let amounts = (
+            Some(3000.),
+            HashMap::from([("IE000BROAD01".to_string(), 1.)]),
+        );
+        let weights = HashMap::from([
+            ("IE000BROAD01".to_string(), 0.5),
+            ("IE000SINGLE1".to_string(), 0.5),
+        ]);
+        let (total, merged) = merge_portfolios(vec![
+            ("a.csv".to_string(), amounts.clone()),
+            ("b.csv".to_string(), (Some(1000.), weights.clone())),
+        ])
+        .unwrap();
+        assert_eq!(total, Some(4000.));
+        assert_eq!(merged["IE000BROAD01"], 0.875);
+        assert_eq!(merged["IE000SINGLE1"], 0.125);
+        // Weights without a total value can't be merged
+        assert!(merge_portfolios(vec![
+            ("a.csv".to_string(), amounts),
+            ("b.csv".to_string(), (None, weights)),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn duplicate_headers_are_an_error() {
+        let headers = csv::StringRecord::from(vec!["ISIN", "Amount", "ISIN"]);
+        let err = check_duplicate_headers(&headers, "p.csv").unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate columns ISIN in p.csv");
+        let headers = csv::StringRecord::from(vec!["ISIN", "Amount"]);
+        assert!(check_duplicate_headers(&headers, "p.csv").is_ok());
+    }
+
+    #[test]
+    fn securities_weights_in_basis_points() {
+        let path = temp_file(
+            "securities-bps.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,6000,Technology,10000,United States,10000,Americas,10000\n\
+             ,,,JPMorgan,4000,,,,,,\n",
+        );
+        let conf = Conf {
+            weight_unit: WeightUnit::Bps,
+            ..conf()
+        };
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf);
+        fs::remove_file(&path).unwrap();
+        let securities = result.unwrap();
+        assert_weights(
+            &exposure(&securities, Exposure::Holding, "IE000BROAD01"),
+            &[("Apple", 0.6), ("JPMorgan", 0.4)],
+        );
+        assert_weights(
+            &exposure(&securities, Exposure::Sector, "IE000BROAD01"),
+            &[("Technology", 1.)],
+        );
+    }
+
+    #[test]
+    fn normalized_securities_weights() {
+        let path = temp_file(
+            "securities-normalize.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,30,Technology,45,United States,90,Americas,90\n\
+             ,,,,,Financial Services,45,,,,\n",
+        );
+        let conf = Conf {
+            normalize_weights: true,
+            ..conf()
+        };
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf);
+        fs::remove_file(&path).unwrap();
+        let securities = result.unwrap();
+        assert_weights(
+            &exposure(&securities, Exposure::Sector, "IE000BROAD01"),
+            &[("Technology", 0.5), ("Financial Services", 0.5)],
+        );
+        assert_weights(
+            &exposure(&securities, Exposure::Country, "IE000BROAD01"),
+            &[("United States", 1.)],
+        );
+        // Holdings are left as listed
+        assert_weights(
+            &exposure(&securities, Exposure::Holding, "IE000BROAD01"),
+            &[("Apple", 0.3)],
+        );
+    }
+
+    #[test]
+    fn regions_estimated_from_markets() {
+        let path = temp_file(
+            "securities-markets.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight,Market,MarketWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,100,Technology,100,,,,,Developed,100\n",
+        );
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf());
+        fs::remove_file(&path).unwrap();
+        let securities = result.unwrap();
+        let regions = exposure(&securities, Exposure::Region, "IE000BROAD01");
+        assert!((regions["Americas"] - 0.74).abs() < 1e-6, "{:?}", regions);
+        assert!(
+            (regions.values().sum::<f32>() - 1.).abs() < 1e-4,
+            "{:?}",
+            regions
+        );
+    }
+
+    #[test]
+    fn regions_collapsed_into_continents() {
+        let path = temp_file(
+            "securities-continents.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight,Region,RegionWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,100,Technology,100,United States,60,Americas,60\n\
+             ,,,,,,,Germany,40,Europe,40\n",
+        );
+        let conf = Conf {
+            continents: true,
+            ..conf()
+        };
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf);
+        fs::remove_file(&path).unwrap();
+        let securities = result.unwrap();
+        assert_weights(
+            &exposure(&securities, Exposure::Region, "IE000BROAD01"),
+            &[("North America", 0.6), ("Europe", 0.4)],
+        );
+    }
+
+    #[test]
+    fn portfolio_by_ticker() {
+        let mut securities = single_stock_securities();
+        securities.get_mut("IE000SINGLE1").unwrap().ticker = "NVDX".to_string();
+        securities.get_mut("IE000BROAD01").unwrap().ticker = "BRD".to_string();
+        let path = temp_file("portfolio-tickers.csv", "Ticker,Weight\nnvdx,40\nBRD,60\n");
+        let result = parse_portfolio(&path.to_string_lossy(), &securities, &conf());
+        fs::remove_file(&path).unwrap();
+        let (_, portfolio) = result.unwrap();
+        assert_eq!(portfolio["IE000SINGLE1"], 0.4);
+        assert_eq!(portfolio["IE000BROAD01"], 0.6);
+        // A ticker of another security than the given ISIN is an error
+        let path = temp_file(
+            "portfolio-ticker-mismatch.csv",
+            "ISIN,Ticker,Weight\nIE000SINGLE1,BRD,100\n",
+        );
+        let result = parse_portfolio(&path.to_string_lossy(), &securities, &conf());
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn duration_of_the_bond_sleeve() {
+        let mut securities = single_stock_securities();
+        let portfolio = HashMap::from([
+            ("IE000SINGLE1".to_string(), 0.5),
+            ("IE000BROAD01".to_string(), 0.5),
+        ]);
+        assert_eq!(calculate_duration(&securities, &portfolio).unwrap(), None);
+        securities.get_mut("IE000BROAD01").unwrap().duration = Some(6.);
+        // Averaged over the securities with a duration only
+        assert_eq!(
+            calculate_duration(&securities, &portfolio).unwrap(),
+            Some(6.)
+        );
+    }
+
+    #[test]
+    fn failed_images_keep_the_separate_plots() {
+        let data = vec![
+            (Exposure::Holding, vec![("A".to_string(), 100.)]),
+            (Exposure::Sector, vec![("Technology".to_string(), 100.)]),
+        ];
+        let metrics = Metrics {
+            ter: 0.2,
+            net_ter: None,
+            total_cost: None,
+            duration: None,
+            pe: None,
+            div_growth: None,
+            payout_ratio: None,
+        };
+        let folder = env::temp_dir().join(format!("pea-{}-separate", std::process::id()));
+        // A directory in place of the first image makes its export fail
+        fs::create_dir_all(folder.join("plot-Holding.svg")).unwrap();
+        let conf = Conf {
+            image: true,
+            separate_files: true,
+            output_folder: folder.to_string_lossy().to_string(),
+            output_file_name: OsString::from("plot"),
+            ..conf()
+        };
+        let result = plot_grid(data, None, &metrics, None, None, &conf);
+        let written =
+            ["plot-Holding.html", "plot-Sector.html"].map(|file| folder.join(file).is_file());
+        fs::remove_dir_all(&folder).unwrap();
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(written, [true, true]);
+    }
+
+    #[test]
+    fn failed_image_export_is_not_fatal() {
+        let data = vec![(Exposure::Holding, vec![("A".to_string(), 100.)])];
+        let metrics = Metrics {
+            ter: 0.2,
+            net_ter: None,
+            total_cost: None,
+            duration: None,
+            pe: None,
+            div_growth: None,
+            payout_ratio: None,
+        };
+        let output_file = env::temp_dir()
+            .join(format!("pea-{}-image", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        // The image can't be written over a directory
+        fs::create_dir_all(format!("{}.svg", output_file)).unwrap();
+        let conf = Conf {
+            image: true,
+            ..conf()
+        };
+        let result = write_plot(data, &output_file, None, &metrics, None, None, &conf);
+        let html = Path::new(&format!("{}.html", output_file)).is_file();
+        fs::remove_dir(format!("{}.svg", output_file)).unwrap();
+        fs::remove_file(format!("{}.html", output_file)).ok();
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(html);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn securities_from_sqlite() {
+        let path = env::temp_dir().join(format!("pea-{}-securities.sqlite", std::process::id()));
+        fs::remove_file(&path).ok();
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE securities (ISIN TEXT, Name TEXT, TER REAL, Holding TEXT, \
+                 HoldingWeight REAL, Sector TEXT, SectorWeight REAL, Country TEXT, \
+                 CountryWeight REAL, Region TEXT, RegionWeight REAL);
+                 INSERT INTO securities VALUES ('IE000BROAD01', 'Broad, \"World\"\nETF', 0.2, \
+                 'Apple', 60, 'Technology', 100, 'United States', 100, 'Americas', 100);
+                 INSERT INTO securities VALUES (NULL, NULL, NULL, 'JPMorgan', 40, NULL, NULL, \
+                 NULL, NULL, NULL, NULL);",
+            )
+            .unwrap();
+        drop(connection);
+        let result = parse_securities(Some(path.to_string_lossy().into()), None, &conf());
+        fs::remove_file(&path).unwrap();
+        let securities = result.unwrap();
+        let security = &securities["IE000BROAD01"];
+        assert_eq!(security.name, "Broad, \"World\"\nETF");
+        assert_eq!(security.ter, 0.2);
+    }
+
+    #[test]
+    fn currency_blocs_keep_their_colors() {
+        let palette = parse_palette("#1F77B4").unwrap();
+        let conf = conf();
+        for bloc in COUNTRY_TO_BLOC.values() {
+            assert_eq!(label_color(bloc, &palette, &conf), "#1F77B4", "{}", bloc);
+        }
+    }
+
+    #[test]
+    fn holdings_grouped_by_issuer() {
+        let issuers = HashMap::from([
+            ("GOOGL".to_string(), "Alphabet".to_string()),
+            ("GOOG".to_string(), "Alphabet".to_string()),
+        ]);
+        let results = vec![
+            ("AAPL".to_string(), 30.),
+            ("GOOGL".to_string(), 20.),
+            (UNKNOWN.to_string(), 25.),
+            ("GOOG".to_string(), 15.),
+            ("TSLA".to_string(), 10.),
+        ];
+        // The unknown share stays a residual bucket after the issuers
+        assert_eq!(
+            group_holdings(results, &issuers, UNKNOWN),
+            [
+                ("Alphabet".to_string(), 35.),
+                ("AAPL".to_string(), 30.),
+                ("TSLA".to_string(), 10.),
+                (UNKNOWN.to_string(), 25.),
+            ]
+        );
+    }
+
+    #[test]
+    fn unmapped_sectors_and_countries() {
+        let path = temp_file(
+            "securities-unmapped.csv",
+            "ISIN,Name,TER,Holding,HoldingWeight,Sector,SectorWeight,Country,CountryWeight\n\
+             IE000BROAD01,Broad,0.2,Apple,60,Technology,60,United States,60\n\
+             ,,,Mystery,40,Alchemy,40,Atlantis,40\n",
+        );
+        let result = find_unmapped(Some(path.to_string_lossy().into()), None);
+        fs::remove_file(&path).unwrap();
+        let unmapped = result.unwrap();
+        let isins = BTreeSet::from(["IE000BROAD01".to_string()]);
+        assert_eq!(
+            unmapped,
+            Unmapped::from([
+                (
+                    ("Continent".to_string(), "Atlantis".to_string()),
+                    isins.clone()
+                ),
+                (
+                    ("CurrencyBloc".to_string(), "Atlantis".to_string()),
+                    isins.clone()
+                ),
+                (
+                    ("Market".to_string(), "Atlantis".to_string()),
+                    isins.clone()
+                ),
+                (
+                    ("Region".to_string(), "Atlantis".to_string()),
+                    isins.clone()
+                ),
+                (("Sector".to_string(), "Alchemy".to_string()), isins),
+            ])
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Golden-file tests running the analysis end to end on the portfolios in `tests/fixtures` and
+//! comparing the JSON report written by `--append-history` with the files in `tests/golden`.
+//!
+//! The fixture securities cover a fund with continuation rows and sector synonyms, a fund of funds,
+//! a stock and a cash account without any data. After an intended change in the results, rewrite
+//! the golden files with `UPDATE_GOLDEN=1 cargo test --test golden` and review their diff.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde_json::Value;
+
+fn path(dir: &str, name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(dir)
+        .join(name)
+}
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// Analyze a fixture portfolio and return its report
+fn analyze(portfolio: &str, args: &[&str]) -> Value {
+    run(portfolio, args).0
+}
+
+/// Analyze a fixture portfolio and return its report and HTML output
+fn run(portfolio: &str, args: &[&str]) -> (Value, String) {
+    let output_folder = env::temp_dir().join(format!(
+        "pea-golden-{}-{}",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&output_folder).unwrap();
+    let history = output_folder.join("history.jsonl");
+    let output = Command::new(env!("CARGO_BIN_EXE_portfolio-exposure-analyzer"))
+        .arg(path("fixtures", "securities.csv"))
+        .arg(path("fixtures", &format!("{}.csv", portfolio)))
+        .arg("-o")
+        .arg(&output_folder)
+        .args(["--as-of", "2024-01-31", "--append-history"])
+        .arg(&history)
+        .args(args)
+        .output()
+        .unwrap();
+    let report = fs::read_to_string(&history);
+    let html = fs::read_to_string(output_folder.join(format!("{}.html", portfolio)));
+    fs::remove_dir_all(&output_folder).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    (
+        serde_json::from_str(&report.unwrap()).unwrap(),
+        html.unwrap(),
+    )
+}
+
+fn assert_golden(name: &str, report: &Value) {
+    let golden = path("golden", &format!("{}.json", name));
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(
+            &golden,
+            serde_json::to_string_pretty(report).unwrap() + "\n",
+        )
+        .unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&golden).unwrap_or_else(|err| {
+        panic!(
+            "{}: {}, create it with UPDATE_GOLDEN=1",
+            golden.display(),
+            err
+        )
+    });
+    assert_eq!(
+        report,
+        &serde_json::from_str::<Value>(&expected).unwrap(),
+        "{} differs from its golden file",
+        name
+    );
+}
+
+#[test]
+fn portfolio_with_amounts() {
+    assert_golden("portfolio_amount", &analyze("portfolio_amount", &[]));
+}
+
+#[test]
+fn portfolio_with_weights() {
+    let report = analyze("portfolio_weight", &[]);
+    assert_golden("portfolio_weight", &report);
+    // The same allocation as amounts or as weights has the same exposures
+    assert_eq!(
+        report["exposures"],
+        analyze("portfolio_amount", &[])["exposures"]
+    );
+}
+
+#[test]
+fn portfolio_with_missing_weights() {
+    assert_golden("portfolio_partial", &analyze("portfolio_partial", &[]));
+}
+
+#[test]
+fn portfolio_on_classified_basis() {
+    let report = analyze("portfolio_partial", &["--exposure-basis", "classified"]);
+    assert_golden("portfolio_partial_classified", &report);
+    // Every exposure with classified labels adds up to 100% without an unknown share
+    for (exposure, results) in report["exposures"].as_object().unwrap() {
+        let results = results.as_object().unwrap();
+        if results.keys().all(|label| label == "Unknown") {
+            continue;
+        }
+        assert!(!results.contains_key("Unknown"), "{}", exposure);
+        let sum = results.values().map(|v| v.as_f64().unwrap()).sum::<f64>();
+        assert!((sum - 100.).abs() < 0.05, "{} adds up to {}", exposure, sum);
+    }
+}
+
+#[test]
+fn plotly_embedded_by_default() {
+    // The embedded plotly.js mentions its CDN as well, so only the script tag tells them apart
+    const CDN: &str = "<script src=\"https://cdn.plot.ly/";
+    let (_, html) = run("portfolio_weight", &[]);
+    assert!(!html.contains(CDN));
+    let (_, html) = run("portfolio_weight", &["--plotly-source", "cdn"]);
+    assert!(html.contains(CDN));
+}